@@ -29,17 +29,38 @@ pub enum SubCommand {
     Lmtp(Lmtp),
     Daemon(Daemon),
     Transport(Transport),
+    Queue(Queue),
 }
 
 #[derive(FromArgs)]
 /// Send email from stdin
 #[argh(subcommand, name = "pipe")]
-pub struct Pipe {}
+pub struct Pipe {
+    #[argh(option, short = 'f')]
+    /// envelope sender
+    pub sender: Option<String>,
+    #[argh(option)]
+    /// address of the client which sent the message
+    pub client_address: Option<String>,
+    #[argh(option)]
+    /// original recipient, in the same order as the recipients
+    pub original_recipient: Vec<String>,
+    #[argh(positional)]
+    /// envelope recipients (the message is sent as is without them)
+    pub recipients: Vec<String>,
+}
 
 #[derive(FromArgs)]
 /// Print aliases
 #[argh(subcommand, name = "aliases")]
-pub struct Aliases {}
+pub struct Aliases {
+    #[argh(switch)]
+    /// print the changes without writing the map
+    pub dry_run: bool,
+    #[argh(switch)]
+    /// replace the map even if it loses too many entries
+    pub force: bool,
+}
 
 #[derive(FromArgs)]
 /// Refresh aliases from a webhook
@@ -84,4 +105,83 @@ pub struct Daemon {
 #[derive(FromArgs)]
 /// Generate transport file
 #[argh(subcommand, name = "transport")]
-pub struct Transport {}
+pub struct Transport {
+    #[argh(switch)]
+    /// print the changes without writing the map
+    pub dry_run: bool,
+    #[argh(switch)]
+    /// replace the map even if it loses too many entries
+    pub force: bool,
+}
+
+#[derive(FromArgs)]
+/// Manage the spooled messages
+#[argh(subcommand, name = "queue")]
+pub struct Queue {
+    #[argh(subcommand)]
+    pub subcmd: QueueCommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum QueueCommand {
+    List(QueueList),
+    Show(QueueShow),
+    Retry(QueueRetry),
+    Delete(QueueDelete),
+    Hold(QueueHold),
+    Release(QueueRelease),
+}
+
+#[derive(FromArgs)]
+/// List the spooled messages
+#[argh(subcommand, name = "list")]
+pub struct QueueList {}
+
+#[derive(FromArgs)]
+/// Print a spooled message
+#[argh(subcommand, name = "show")]
+pub struct QueueShow {
+    #[argh(positional)]
+    /// message id
+    pub id: String,
+}
+
+#[derive(FromArgs)]
+/// Deliver spooled messages at the next run of the daemon
+#[argh(subcommand, name = "retry")]
+pub struct QueueRetry {
+    #[argh(positional)]
+    /// message id
+    pub id: Option<String>,
+    #[argh(switch, short = 'a')]
+    /// retry all the messages
+    pub all: bool,
+}
+
+#[derive(FromArgs)]
+/// Delete a spooled message
+#[argh(subcommand, name = "delete")]
+pub struct QueueDelete {
+    #[argh(positional)]
+    /// message id
+    pub id: String,
+}
+
+#[derive(FromArgs)]
+/// Stop retrying a spooled message
+#[argh(subcommand, name = "hold")]
+pub struct QueueHold {
+    #[argh(positional)]
+    /// message id
+    pub id: String,
+}
+
+#[derive(FromArgs)]
+/// Retry a held message
+#[argh(subcommand, name = "release")]
+pub struct QueueRelease {
+    #[argh(positional)]
+    /// message id
+    pub id: String,
+}
@@ -1,10 +1,33 @@
+use crate::args::Aliases;
 use crate::config::Config;
-use crate::utils::MapType;
+use crate::recipients::Recipients;
+use crate::utils::{MapType, MapsLock};
 use anyhow::Result;
 
-pub fn cmd(config: &Config) -> Result<Option<String>> {
-    if let Ok(data) = MapType::Aliases.get(config) {
-        MapType::Aliases.write(config, &data.as_bytes())?;
+/// Get aliases from odoo and write the map, returning its content
+pub async fn update(config: &Config, force: bool) -> Result<Option<String>> {
+    if let Ok(data) = MapType::Aliases.get(config).await {
+        let lock = MapsLock::acquire(config).await?;
+        let recipients = Recipients::default().shared();
+        MapType::Aliases
+            .write(config, &data, force, &recipients, &lock)
+            .await?;
+        return Ok(Some(data));
     }
     Ok(None)
 }
+
+pub async fn cmd(config: &Config, args: Aliases) -> Result<Option<String>> {
+    if args.dry_run {
+        let data = MapType::Aliases.get(config).await?;
+        print!(
+            "{}",
+            MapType::Aliases
+                .check(config, &data, args.force, &Recipients::default().shared())
+                .await?
+        );
+        return Ok(None);
+    }
+    update(config, args.force).await?;
+    Ok(None)
+}
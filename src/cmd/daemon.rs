@@ -1,41 +1,98 @@
 use crate::{
     args::{Daemon, Lmtp, Webhook},
     cmd::{
-        aliases::cmd as aliases, lmtp::cmd as lmtp, transport::cmd as transport,
-        webhook::cmd as webhook,
+        aliases::update as aliases, lmtp::serve as lmtp, policy::serve as policy,
+        socketmap::serve as socketmap, transport::update as transport, webhook::serve as webhook,
     },
     config::Config,
-    utils::s6_ready,
+    recipients::Recipients,
+    refresh,
+    utils::{s6_ready, shutdown},
 };
 use anyhow::Result;
-use std::thread;
 
-pub fn cmd(config: Config, args: Daemon, verbose: bool, debug: bool) -> Result<Option<String>> {
-    // get aliases
-    aliases(&config)?;
+pub async fn cmd(
+    config: Config,
+    args: Daemon,
+    verbose: bool,
+    debug: bool,
+) -> Result<Option<String>> {
+    // get aliases, keeping the current maps if they are refused
+    let aliases = aliases(&config, false).await.unwrap_or_else(|e| {
+        eprintln!("can't update aliases: {}", e);
+        None
+    });
     // get transport
-    transport(&config)?;
+    let transport = transport(&config, false).await.unwrap_or_else(|e| {
+        eprintln!("can't update transport: {}", e);
+        None
+    });
+    // keep them in memory for recipients validation
+    let recipients = match (aliases, transport) {
+        (Some(aliases), Some(transport)) => Recipients::new(&aliases, &transport),
+        _ => Recipients::default(),
+    }
+    .shared();
 
-    // launch webhook and lmtp
+    // launch webhook, lmtp, the refresh of the maps, and socketmap and policy if configured
     let webhook_args = Webhook {
         port: args.port,
         prefix: args.prefix,
         ready_fd: None,
     };
     let lmtp_args = Lmtp { ready_fd: None };
-    let wconfig = config.clone();
-    let webhook = thread::spawn(move || webhook(wconfig, webhook_args, verbose));
-    let lmtp = thread::spawn(move || lmtp(config, lmtp_args, verbose, debug));
+    let webhook = webhook(config.clone(), webhook_args, verbose, recipients.clone());
+    let socketmap = async {
+        match &config.socketmap {
+            Some(address) => socketmap(&config, address, recipients.clone()).await,
+            None => std::future::pending().await,
+        }
+    };
+    let refresh = refresh::run(&config, recipients.clone());
+    let policy = async {
+        match &config.policy {
+            Some(address) => policy(address, recipients.clone()).await,
+            None => std::future::pending().await,
+        }
+    };
+    let lmtp = lmtp(
+        config.clone(),
+        lmtp_args,
+        verbose,
+        debug,
+        recipients.clone(),
+    );
 
     // s6 readiness notification
     s6_ready(args.ready_fd);
 
-    // wait for threads to finish
-    if let Err(_) = webhook.join() {
-        eprintln!("webhook error");
-    };
-    if let Err(_) = lmtp.join() {
-        eprintln!("lmtp error");
-    };
-    Ok(None)
+    // run until one of the servers fails or a signal is received
+    tokio::select! {
+        res = webhook => {
+            if let Err(e) = &res {
+                eprintln!("webhook error: {}", e);
+            }
+            res
+        }
+        res = lmtp => {
+            if let Err(e) = &res {
+                eprintln!("lmtp error: {}", e);
+            }
+            res
+        }
+        res = socketmap => {
+            if let Err(e) = &res {
+                eprintln!("socketmap error: {}", e);
+            }
+            res.map(|_| None)
+        }
+        res = policy => {
+            if let Err(e) = &res {
+                eprintln!("policy error: {}", e);
+            }
+            res.map(|_| None)
+        }
+        _ = refresh => Ok(None),
+        _ = shutdown() => Ok(None),
+    }
 }
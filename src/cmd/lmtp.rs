@@ -1,18 +1,39 @@
-use crate::{args::Lmtp, config::Config, utils::s6_ready};
-use anyhow::Result;
-use bufstream::BufStream;
+use crate::{
+    args::Lmtp,
+    config::{Config, Protocol},
+    delivery::{deliver, Delivery},
+    envelope::Envelope,
+    recipients::{Recipients, SharedRecipients},
+    refresh,
+    spool::Spool,
+    status::StatusMap,
+    tls::server_config,
+    utils::{s6_ready, shutdown},
+};
+use anyhow::{Context as _, Result};
 use std::{
-    fs::{remove_file, set_permissions, File, Permissions},
-    io::{BufRead, Write},
-    os::unix::{
-        fs::PermissionsExt,
-        net::{UnixListener, UnixStream},
-    },
+    fs::{remove_file, set_permissions, Permissions},
+    io,
+    os::unix::fs::PermissionsExt,
     sync::Arc,
-    thread,
     time::{Duration, SystemTime},
 };
-use ureq::post;
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::{TcpListener, UnixListener},
+    sync::Semaphore,
+};
+use tokio_io_timeout::TimeoutStream;
+use tokio_rustls::TlsAcceptor;
+
+// maximum length of a line read at once (RFC 5321 §4.5.3.1.6): longer commands are refused and
+// longer content lines are read in pieces
+const MAX_LINE: usize = 1000;
+// maximum duration of the tls handshake of a connection rejected because of the session limit
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[macro_export]
 macro_rules! return_on_err(
@@ -23,127 +44,568 @@ macro_rules! return_on_err(
     }
 );
 
-static OK: &'static str = "250 OK\r\n";
+/// Format a reply with its enhanced status code (RFC 2034)
+fn reply(code: u16, status: &str, text: &str) -> String {
+    format!("{} {} {}\r\n", code, status, text)
+}
+
+/// Reply to a command received in the wrong state
+fn bad_sequence(text: &str) -> String {
+    reply(503, "5.5.1", text)
+}
+
+/// Client connection. Replies are queued and only sent when no other pipelined command is
+/// waiting in the input buffer (RFC 2920)
+struct Connection<S: AsyncRead + AsyncWrite + Unpin> {
+    reader: BufReader<S>,
+    replies: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    fn new(stream: S) -> Self {
+        Connection {
+            reader: BufReader::new(stream),
+            replies: Vec::new(),
+        }
+    }
 
+    fn reply(&mut self, reply: &str) {
+        self.replies.extend_from_slice(reply.as_bytes());
+    }
+
+    /// Send the queued replies
+    async fn flush(&mut self) -> io::Result<()> {
+        let stream = self.reader.get_mut();
+        stream.write_all(&self.replies).await?;
+        self.replies.clear();
+        stream.flush().await
+    }
+
+    /// Send the queued replies if there is no more buffered commands to process
+    async fn sync(&mut self) -> io::Result<()> {
+        if self.reader.buffer().is_empty() {
+            self.flush().await
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Session state, enforcing the order of commands (RFC 5321 §4.1.4)
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    // waiting for LHLO
+    Connected,
+    // waiting for MAIL
+    Greeted,
+    // waiting for RCPT
+    Mail,
+    // waiting for RCPT, DATA or BDAT
+    Rcpt,
+    // receiving BDAT chunks
+    Chunking,
+}
+
+/// Per transaction state, cleared by RSET, LHLO and at the end of the message
+#[derive(Default)]
+struct Transaction {
+    // reverse path, empty for bounces
+    sender: String,
+    // accepted recipients
+    rcpts: Vec<String>,
+    // raw message content: mail is not required to be valid utf-8
+    data: Vec<u8>,
+    // size of the received content, which is not kept past the maximum size
+    size: usize,
+    // BODY=BINARYMIME: content must be transferred with BDAT and is left untouched
+    binary: bool,
+}
+
+impl Transaction {
+    /// Post the message to odoo for one recipient and return the reply
+    async fn deliver(&self, server: &Server, rcpt: &str) -> String {
+        let config = &server.config;
+        let envelope = Envelope {
+            sender: &self.sender,
+            recipient: rcpt,
+            original_recipient: None,
+            client_address: None,
+        };
+        // binary content is left untouched, otherwise line endings are normalized to LF
+        let eol = if self.binary { "\r\n" } else { "\n" };
+        let content = [envelope.headers(eol).as_bytes(), &self.data].concat();
+        // limit the number of concurrent requests to odoo
+        let permit = server.deliveries.acquire().await;
+        let delivery = deliver(config, content.clone()).await;
+        drop(permit);
+
+        let (code, status) = server.status_map.map(&delivery);
+        match (&delivery, &server.spool) {
+            (Delivery::Delivered(_), _) => reply(code, status, &format!("<{}> delivered", rcpt)),
+            // keep the message until odoo is back
+            (_, Some(spool)) if code < 500 => {
+                match spool
+                    .add(&self.sender, rcpt, &content, &delivery.to_string())
+                    .await
+                {
+                    Ok(id) => {
+                        if server.verbose {
+                            eprintln!("message for {} spooled as {}: {}", rcpt, id, delivery);
+                        }
+                        reply(250, "2.0.0", &format!("<{}> queued as {}", rcpt, id))
+                    }
+                    Err(e) => {
+                        eprintln!("can't spool message: {}", e);
+                        reply(code, status, &delivery.to_string())
+                    }
+                }
+            }
+            _ => reply(code, status, &delivery.to_string()),
+        }
+    }
+
+    /// Check that the message is not too big to be delivered, the size of chunks overflowing
+    /// usize being saturated
+    fn too_big(&self, config: &Config) -> bool {
+        self.size == usize::MAX || (config.max_size > 0 && self.size > config.max_size)
+    }
+}
+
+/// Per connection state
 struct Context {
-    data: String,
+    protocol: Protocol,
+    state: State,
+    transaction: Transaction,
     quit: bool,
-    crlf: bool,
 }
 
 impl Context {
-    fn deliver(&self, config: &Config) -> String {
-        let url = format!("https://{}/mail_delivery/pipe", &config.host);
-        let resp = post(&url)
-            .timeout_connect(10_000)
-            .set("X-Mail-Token", &config.token)
-            .set("Content-Type", "text/plain")
-            .send_string(&self.data);
-
-        if resp.ok() {
-            OK.to_string()
+    /// Abort the current transaction if any
+    fn reset(&mut self) {
+        self.transaction = Transaction::default();
+        if self.state != State::Connected {
+            self.state = State::Greeted;
+        }
+    }
+
+    /// Reply to LHLO in lmtp, EHLO or HELO (without extensions) in smtp
+    fn hello(&mut self, cmd: &str, arg: &str, config: &Config) -> String {
+        match (self.protocol, cmd) {
+            (Protocol::Lmtp, "lhlo") | (Protocol::Smtp, "ehlo") | (Protocol::Smtp, "helo") => (),
+            _ => return reply(500, "5.5.1", "Invalid command"),
+        }
+        if arg.is_empty() {
+            return reply(
+                501,
+                "5.5.4",
+                &format!("Syntax: {} hostname", cmd.to_ascii_uppercase()),
+            );
+        }
+        self.state = State::Greeted;
+        self.reset();
+        if cmd == "helo" {
+            return format!("250 {}\r\n", arg);
+        }
+        format!(
+            "250-{}\r\n250-PIPELINING\r\n250-SIZE {}\r\n250-ENHANCEDSTATUSCODES\r\n\
+             250-8BITMIME\r\n250-CHUNKING\r\n250 BINARYMIME\r\n",
+            arg, config.max_size
+        )
+    }
+
+    fn mail(&mut self, arg: &str, config: &Config) -> String {
+        match self.state {
+            State::Connected => return bad_sequence("Send hello first"),
+            State::Greeted => (),
+            _ => return bad_sequence("Nested MAIL command"),
+        }
+        let size = parse_param(arg, "size")
+            .and_then(|size| size.parse::<usize>().ok())
+            .unwrap_or(0);
+        match parse_path(arg, "from:") {
+            Some(_) if config.max_size > 0 && size > config.max_size => {
+                reply(552, "5.3.4", "Message size exceeds fixed limit")
+            }
+            Some(sender) => {
+                self.transaction.sender = sender.to_string();
+                self.transaction.binary = parse_param(arg, "body")
+                    .is_some_and(|body| body.eq_ignore_ascii_case("binarymime"));
+                self.state = State::Mail;
+                reply(250, "2.1.0", "Sender OK")
+            }
+            None => reply(501, "5.5.4", "Syntax: MAIL FROM:<address>"),
+        }
+    }
+
+    fn rcpt(&mut self, arg: &str, recipients: &SharedRecipients) -> String {
+        match self.state {
+            State::Mail | State::Rcpt => (),
+            State::Chunking => return bad_sequence("BDAT in progress"),
+            _ => return bad_sequence("Need MAIL command"),
+        }
+        match parse_path(arg, "to:") {
+            Some(rcpt) if recipients.read().unwrap().contains(rcpt) => {
+                self.transaction.rcpts.push(rcpt.to_string());
+                self.state = State::Rcpt;
+                reply(250, "2.1.5", "Recipient OK")
+            }
+            Some(rcpt) => reply(
+                550,
+                "5.1.1",
+                &format!(
+                    "<{}>: Recipient address rejected: unknown odoo address",
+                    rcpt
+                ),
+            ),
+            None => reply(501, "5.5.4", "Syntax: RCPT TO:<address>"),
+        }
+    }
+
+    /// Check that the message content can be received with DATA
+    fn can_data(&self) -> Result<(), String> {
+        match self.state {
+            State::Rcpt if self.transaction.binary => Err(bad_sequence("Use BDAT with BINARYMIME")),
+            State::Rcpt => Ok(()),
+            State::Chunking => Err(bad_sequence("BDAT in progress")),
+            State::Mail => Err(bad_sequence("No valid recipients")),
+            _ => Err(bad_sequence("Need MAIL command")),
+        }
+    }
+
+    /// Check that the message content can be received with BDAT
+    fn can_bdat(&self) -> Result<(), String> {
+        match self.state {
+            State::Rcpt | State::Chunking => Ok(()),
+            State::Mail => Err(bad_sequence("No valid recipients")),
+            _ => Err(bad_sequence("Need MAIL command")),
+        }
+    }
+
+    /// Finish the transaction and return one reply per accepted recipient in lmtp (RFC 2033)
+    /// or a single reply in smtp
+    async fn end_data(&mut self, server: &Server) -> String {
+        let transaction = &self.transaction;
+        let res = if transaction.too_big(&server.config) {
+            let res = reply(552, "5.3.4", "Message size exceeds fixed limit");
+            transaction.rcpts.iter().map(|_| res.clone()).collect()
+        } else {
+            let mut res = Vec::new();
+            for rcpt in &transaction.rcpts {
+                res.push(transaction.deliver(server, rcpt).await);
+            }
+            res
+        };
+        let res = match self.protocol {
+            Protocol::Lmtp => res.concat(),
+            Protocol::Smtp => smtp_reply(&transaction.sender, &transaction.rcpts, res),
+        };
+        self.reset();
+        res
+    }
+}
+
+/// Single smtp reply from the replies of each recipient. The message is accepted when it was
+/// delivered or spooled for any recipient, since the relay would bounce or duplicate the whole
+/// message: the failures for the other recipients are only logged.
+fn smtp_reply(sender: &str, rcpts: &[String], res: Vec<String>) -> String {
+    if !res.iter().any(|res| res.starts_with('2')) {
+        // a temporary failure first, so that the relay retries
+        return res
+            .into_iter()
+            .min_by_key(|res| !res.starts_with('4'))
+            .unwrap_or_else(|| reply(250, "2.0.0", "Message accepted"));
+    }
+    for (rcpt, res) in rcpts.iter().zip(&res) {
+        if !res.starts_with('2') {
+            eprintln!(
+                "ALERT message from <{}> accepted but not delivered to <{}>: {}",
+                sender,
+                rcpt,
+                res.trim_end()
+            );
+        }
+    }
+    reply(250, "2.0.0", "Message accepted")
+}
+
+/// Extract the address of a reverse or forward path argument (`TO:<address> params`)
+fn parse_path<'a>(arg: &'a str, prefix: &str) -> Option<&'a str> {
+    if arg.len() < prefix.len() || !arg[..prefix.len()].eq_ignore_ascii_case(prefix) {
+        return None;
+    }
+    let path = arg[prefix.len()..].trim_start();
+    let path = path.split(' ').next().unwrap_or("");
+    Some(path.trim_start_matches('<').trim_end_matches('>'))
+}
+
+/// Get the value of an esmtp parameter following the path (`FROM:<address> SIZE=1234`)
+fn parse_param<'a>(arg: &'a str, name: &str) -> Option<&'a str> {
+    arg.split(' ').skip(1).find_map(|param| {
+        let mut param = param.splitn(2, '=');
+        match (param.next(), param.next()) {
+            (Some(key), value) if key.eq_ignore_ascii_case(name) => Some(value.unwrap_or("")),
+            _ => None,
+        }
+    })
+}
+
+/// Write partial data to /tmp for debuging purpose
+async fn dump(data: &[u8]) {
+    if !data.is_empty() {
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let _ = tokio::fs::write(format!("/tmp/lmtp_{}", time), data).await;
+    }
+}
+
+/// Read the message content up to the terminating dot line (RFC 5321 §4.5.2), removing the
+/// dot stuffing and normalizing line endings to LF. Return the size of the content or None if
+/// the stream ended before the terminating line. Past max_size (if not 0) the content is
+/// discarded, so that memory is bounded even by a line that never ends.
+async fn read_data<R: AsyncBufRead + Unpin>(
+    stream: &mut R,
+    data: &mut Vec<u8>,
+    max_size: usize,
+) -> io::Result<Option<usize>> {
+    let mut line = Vec::new();
+    let mut size = 0;
+    // false while reading the rest of a line longer than MAX_LINE
+    let mut start = true;
+    loop {
+        line.clear();
+        if (&mut *stream)
+            .take(MAX_LINE as u64)
+            .read_until(b'\n', &mut line)
+            .await?
+            == 0
+        {
+            return Ok(None);
+        }
+        // clients should send CRLF but bare LF is tolerated
+        let (content, end) = match line.strip_suffix(b"\n") {
+            Some(content) => (content.strip_suffix(b"\r").unwrap_or(content), true),
+            None => (&line[..], false),
+        };
+        if start && end && content == b"." {
+            return Ok(Some(size));
+        }
+        let content = if start {
+            content.strip_prefix(b".").unwrap_or(content)
         } else {
-            let code = resp.status();
-            let msg = match resp.into_string() {
-                Ok(msg) => msg,
-                _ => "".to_string(),
-            };
-            format!("421 {} ({})\r\n", msg, code)
+            content
+        };
+        size += content.len() + end as usize;
+        if max_size == 0 || size <= max_size {
+            data.extend_from_slice(content);
+            if end {
+                data.push(b'\n');
+            }
+        }
+        start = end;
+    }
+}
+
+/// Read a command line of at most MAX_LINE octets. A longer line is discarded up to its end
+/// and Ok(None) is returned.
+async fn read_command<R: AsyncBufRead + Unpin>(
+    stream: &mut R,
+    command: &mut Vec<u8>,
+) -> io::Result<Option<usize>> {
+    let read = (&mut *stream)
+        .take(MAX_LINE as u64)
+        .read_until(b'\n', command)
+        .await?;
+    if read < MAX_LINE || command.ends_with(b"\n") {
+        return Ok(Some(read));
+    }
+    let mut rest = Vec::new();
+    loop {
+        rest.clear();
+        let read = (&mut *stream)
+            .take(MAX_LINE as u64)
+            .read_until(b'\n', &mut rest)
+            .await?;
+        if read == 0 || rest.ends_with(b"\n") {
+            return Ok(None);
+        }
+    }
+}
+
+/// Normalize CRLF line endings to LF
+fn normalize(data: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(data.len());
+    let mut bytes = data.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        if byte != b'\r' || bytes.peek() != Some(&&b'\n') {
+            normalized.push(byte);
         }
     }
+    normalized
+}
+
+/// Read a BDAT chunk of exactly size bytes (RFC 3030), appending it to data only if keep is
+/// true. Return false if the stream ended before the end of the chunk.
+async fn read_chunk<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    data: &mut Vec<u8>,
+    size: usize,
+    keep: bool,
+) -> io::Result<bool> {
+    let mut chunk = stream.take(size as u64);
+    let read = if keep {
+        chunk.read_to_end(data).await?
+    } else {
+        tokio::io::copy(&mut chunk, &mut tokio::io::sink()).await? as usize
+    };
+    Ok(read == size)
 }
 
-fn handle_client(stream: UnixStream, config: Arc<Config>, verbose: bool, debug: bool) {
-    let _ = stream.set_read_timeout(Some(Duration::new(5, 0)));
-    let mut stream = BufStream::new(stream);
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    protocol: Protocol,
+    server: &Server,
+) {
+    let config = &server.config;
+    let mut stream = Connection::new(stream);
     let mut l = Context {
-        data: String::new(),
+        protocol,
+        state: State::Connected,
+        transaction: Transaction::default(),
         quit: false,
-        crlf: false,
     };
-    return_on_err!(stream.write(b"220 localhost LMTP server ready\r\n"));
-    return_on_err!(stream.flush());
+    stream.reply(match protocol {
+        Protocol::Lmtp => "220 localhost LMTP server ready\r\n",
+        Protocol::Smtp => "220 localhost ESMTP server ready\r\n",
+    });
+    return_on_err!(stream.flush().await);
     loop {
-        let mut command = String::new();
-        match stream.read_line(&mut command) {
-            Ok(_) => {
+        let mut command = Vec::new();
+        match read_command(&mut stream.reader, &mut command).await {
+            Ok(None) => {
+                stream.reply(&reply(500, "5.5.2", "Line too long"));
+                return_on_err!(stream.sync().await);
+            }
+            Ok(Some(_)) => {
                 if command.is_empty() {
                     return;
                 }
-                let trimmed_command = (&command[..]).trim();
-                let mut args = trimmed_command.split(' ');
-                let invalid = "500 Invalid command\r\n".to_string();
-                let data_res = b"354 Start mail input; end with <CRLF>.<CRLF>\r\n";
-                let ok = OK.to_string();
-                let res = match args.next() {
-                    Some(cmd) => {
-                        if verbose {
-                            eprintln!("{}", trimmed_command);
-                        }
-                        match &cmd.to_ascii_lowercase()[..] {
-                            "lhlo" => match args.next() {
-                                Some(domain) => format!("250 {}\r\n", domain),
-                                _ => invalid,
-                            },
-                            "rset" | "noop" | "mail" | "rcpt" => ok,
-                            "quit" => {
-                                l.quit = true;
-                                "221 localhost Closing connection\r\n".to_string()
+                // commands are ascii, don't fail on garbage
+                let command = String::from_utf8_lossy(&command);
+                let trimmed_command = command.trim();
+                if server.verbose {
+                    eprintln!("{}", trimmed_command);
+                }
+                let (cmd, arg) = match trimmed_command.split_once(' ') {
+                    Some((cmd, arg)) => (cmd, arg.trim()),
+                    None => (trimmed_command, ""),
+                };
+                let cmd = cmd.to_ascii_lowercase();
+                let res = match &cmd[..] {
+                    "lhlo" | "ehlo" | "helo" => l.hello(&cmd, arg, config),
+                    "mail" => l.mail(arg, config),
+                    "rcpt" => l.rcpt(arg, &server.recipients),
+                    "rset" => {
+                        l.reset();
+                        reply(250, "2.0.0", "OK")
+                    }
+                    "noop" => reply(250, "2.0.0", "OK"),
+                    "quit" => {
+                        l.quit = true;
+                        reply(221, "2.0.0", "localhost Closing connection")
+                    }
+                    "data" => match l.can_data() {
+                        Ok(()) => {
+                            // data is a synchronisation point: send the pending replies
+                            stream.reply("354 Start mail input; end with <CRLF>.<CRLF>\r\n");
+                            return_on_err!(stream.flush().await);
+                            let transaction = &mut l.transaction;
+                            match read_data(
+                                &mut stream.reader,
+                                &mut transaction.data,
+                                config.max_size,
+                            )
+                            .await
+                            {
+                                Ok(Some(size)) => {
+                                    transaction.size = size;
+                                    l.end_data(server).await
+                                }
+                                // EOF or timeout before the final dot
+                                _ => {
+                                    if server.debug {
+                                        dump(&transaction.data).await;
+                                    }
+                                    return;
+                                }
                             }
-                            "vrfy" => invalid,
-                            "data" => {
-                                return_on_err!(stream.write(data_res));
-                                return_on_err!(stream.flush());
-                                let mut res = invalid;
-                                loop {
-                                    let mut line = String::new();
-                                    match stream.read_line(&mut line) {
-                                        Ok(_) => {
-                                            if line.is_empty() {
-                                                break;
-                                            }
-                                            if l.crlf && line == ".\r\n" {
-                                                res = l.deliver(&config);
-                                                l.data = String::new();
-                                                break;
-                                            } else {
-                                                if line.ends_with("\r\n") {
-                                                    l.crlf = true
-                                                } else {
-                                                    l.crlf = false
-                                                }
-                                                l.data.push_str(&line);
-                                            }
+                        }
+                        Err(res) => res,
+                    },
+                    "bdat" => {
+                        let mut args = arg.split(' ');
+                        let size = args.next().and_then(|size| size.parse::<usize>().ok());
+                        let last = match args.next() {
+                            Some(last) if last.eq_ignore_ascii_case("last") => Some(true),
+                            Some(_) => None,
+                            None => Some(false),
+                        };
+                        match (size, last) {
+                            (Some(size), Some(last)) => {
+                                let accepted = l.can_bdat();
+                                let transaction = &mut l.transaction;
+                                // the chunk must be read even if it is rejected, and is too big
+                                // if the total size overflows
+                                let total = transaction.size.checked_add(size);
+                                let keep = accepted.is_ok()
+                                    && total.is_some_and(|total| {
+                                        config.max_size == 0 || total <= config.max_size
+                                    });
+                                match read_chunk(
+                                    &mut stream.reader,
+                                    &mut transaction.data,
+                                    size,
+                                    keep,
+                                )
+                                .await
+                                {
+                                    Ok(true) => (),
+                                    // EOF or timeout before the end of the chunk
+                                    _ => {
+                                        if server.debug {
+                                            dump(&transaction.data).await;
                                         }
-                                        // EOF
-                                        _ => {
-                                            // write partial data to /tmp for debuging purpose
-                                            if debug && l.data.len() > 0 {
-                                                let time = SystemTime::now()
-                                                    .duration_since(SystemTime::UNIX_EPOCH)
-                                                    .unwrap()
-                                                    .as_secs();
-                                                let mut file =
-                                                    File::create(format!("/tmp/lmtp_{}", time))
-                                                        .unwrap();
-                                                let _ = file.write(l.data.as_bytes());
-                                            }
-                                            break;
+                                        return;
+                                    }
+                                }
+                                match accepted {
+                                    Ok(()) if last => {
+                                        transaction.size = total.unwrap_or(usize::MAX);
+                                        if !transaction.binary {
+                                            transaction.data = normalize(&transaction.data);
                                         }
+                                        l.end_data(server).await
+                                    }
+                                    Ok(()) => {
+                                        transaction.size = total.unwrap_or(usize::MAX);
+                                        l.state = State::Chunking;
+                                        reply(250, "2.0.0", &format!("{} octets received", size))
                                     }
+                                    Err(res) => res,
                                 }
-                                res
                             }
-                            _ => invalid,
+                            _ => reply(501, "5.5.4", "Syntax: BDAT size [LAST]"),
                         }
                     }
-                    None => invalid,
+                    _ => reply(500, "5.5.2", "Invalid command"),
                 };
-                return_on_err!(stream.write(res.as_bytes()));
-                return_on_err!(stream.flush());
+                stream.reply(&res);
                 if l.quit {
+                    let _ = stream.flush().await;
                     return;
                 }
+                return_on_err!(stream.sync().await);
             }
             _ => {
                 break;
@@ -152,31 +614,490 @@ fn handle_client(stream: UnixStream, config: Arc<Config>, verbose: bool, debug:
     }
 }
 
-pub fn cmd(config: Config, args: Lmtp, verbose: bool, debug: bool) -> Result<Option<String>> {
-    let _ = remove_file(&config.socket);
-    let listener = UnixListener::bind(&config.socket)?;
-    let permissions = Permissions::from_mode(0o666);
-    set_permissions(&config.socket, permissions)?;
+pub async fn cmd(config: Config, args: Lmtp, verbose: bool, debug: bool) -> Result<Option<String>> {
+    // the webhook updates the maps in another process: recipients are only validated when they
+    // are refreshed from odoo, accepting everything until they are available
+    if config.refresh == 0 {
+        println!("recipients not validated without refresh");
+        return tokio::select! {
+            res = serve(config, args, verbose, debug, Recipients::default().shared()) => res,
+            _ = shutdown() => Ok(None),
+        };
+    }
+    let recipients = match Recipients::fetch(&config).await {
+        Ok(recipients) => recipients,
+        Err(e) => {
+            eprintln!(
+                "can't get recipients, validation disabled until then: {}",
+                e
+            );
+            Recipients::default()
+        }
+    }
+    .shared();
+    tokio::select! {
+        res = serve(config.clone(), args, verbose, debug, recipients.clone()) => res,
+        _ = refresh::run(&config, recipients) => Ok(None),
+        _ = shutdown() => Ok(None),
+    }
+}
 
-    println!("lmtp serving at {}", config.socket);
+/// State shared by all the sessions
+struct Server {
+    config: Config,
+    verbose: bool,
+    debug: bool,
+    recipients: SharedRecipients,
+    // limit of concurrent sessions
+    sessions: Arc<Semaphore>,
+    // limit of concurrent deliveries to odoo
+    deliveries: Semaphore,
+    // messages accepted while odoo is unavailable
+    spool: Option<Spool>,
+    // smtp replies to odoo errors
+    status_map: StatusMap,
+}
 
-    // s6 readiness notification
-    s6_ready(args.ready_fd);
+impl Server {
+    fn new(
+        config: Config,
+        verbose: bool,
+        debug: bool,
+        recipients: SharedRecipients,
+    ) -> Result<Self> {
+        Ok(Server {
+            spool: config.spool.as_deref().map(Spool::new).transpose()?,
+            status_map: StatusMap::new(&config.status_map)?,
+            sessions: Arc::new(Semaphore::new(config.max_sessions.max(1))),
+            deliveries: Semaphore::new(config.max_deliveries.max(1)),
+            config,
+            verbose,
+            debug,
+            recipients,
+        })
+    }
+}
 
-    let config = Arc::new(config);
-    // accept connections and process them, spawning a new thread for each one
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                /* connection succeeded */
-                let aconfig = config.clone();
-                thread::spawn(move || handle_client(stream, aconfig, verbose, debug));
-            }
-            Err(_err) => {
-                /* connection failed */
-                break;
+/// Process a connection in its own task, with tls if configured, or reject it when there is
+/// already too many sessions
+fn dispatch<S>(stream: S, protocol: Protocol, tls: Option<TlsAcceptor>, server: &Arc<Server>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let mut stream = TimeoutStream::new(stream);
+    stream.set_read_timeout(Some(Duration::new(5, 0)));
+    let mut stream = Box::pin(stream);
+    let permit = match server.sessions.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            if server.verbose {
+                eprintln!("too many sessions, connection rejected");
             }
+            let greeting = reply(
+                421,
+                "4.3.2",
+                "localhost Too many connections, try again later",
+            );
+            tokio::spawn(async move {
+                match tls {
+                    // the greeting can only be sent after the handshake, limited in time
+                    Some(tls) => {
+                        let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, tls.accept(stream));
+                        if let Ok(Ok(mut stream)) = handshake.await {
+                            let _ = stream.write_all(greeting.as_bytes()).await;
+                            let _ = stream.shutdown().await;
+                        }
+                    }
+                    None => {
+                        let _ = stream.write_all(greeting.as_bytes()).await;
+                    }
+                }
+            });
+            return;
+        }
+    };
+    let server = server.clone();
+    tokio::spawn(async move {
+        let _permit = permit;
+        match tls {
+            Some(tls) => match tls.accept(stream).await {
+                Ok(stream) => handle_client(stream, protocol, &server).await,
+                Err(e) => {
+                    if server.verbose {
+                        eprintln!("tls handshake failed: {}", e);
+                    }
+                }
+            },
+            None => handle_client(stream, protocol, &server).await,
         }
+    });
+}
+
+/// Accept connections on a tcp listener
+async fn listen_tcp(
+    listener: TcpListener,
+    protocol: Protocol,
+    tls: Option<TlsAcceptor>,
+    server: Arc<Server>,
+) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        dispatch(stream, protocol, tls.clone(), &server);
+    }
+}
+
+/// Accept connections on the unix socket
+async fn listen_unix(listener: UnixListener, server: Arc<Server>) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        dispatch(stream, Protocol::Lmtp, None, &server);
+    }
+}
+
+/// Serve lmtp, validating recipients against the shared recipient set
+pub async fn serve(
+    config: Config,
+    args: Lmtp,
+    verbose: bool,
+    debug: bool,
+    recipients: SharedRecipients,
+) -> Result<Option<String>> {
+    let server = Arc::new(Server::new(config, verbose, debug, recipients)?);
+    let config = &server.config;
+
+    // tcp listeners, each one in its own task
+    let mut listeners = tokio::task::JoinSet::new();
+    for listen in &config.listen {
+        let listener = TcpListener::bind(&listen.address)
+            .await
+            .with_context(|| format!("Can't listen on {}", &listen.address))?;
+        let tls = listen
+            .tls
+            .as_ref()
+            .map(server_config)
+            .transpose()?
+            .map(TlsAcceptor::from);
+        let protocol = listen.protocol;
+        println!(
+            "{} serving at {}{}",
+            match protocol {
+                Protocol::Lmtp => "lmtp",
+                Protocol::Smtp => "smtp",
+            },
+            listen.address,
+            if tls.is_some() { " (tls)" } else { "" }
+        );
+        listeners.spawn(listen_tcp(listener, protocol, tls, server.clone()));
+    }
+
+    // unix socket, unless disabled with an empty path
+    if !config.socket.is_empty() {
+        let _ = remove_file(&config.socket);
+        let listener = UnixListener::bind(&config.socket)?;
+        let permissions = Permissions::from_mode(0o666);
+        set_permissions(&config.socket, permissions)?;
+        println!("lmtp serving at {}", config.socket);
+        listeners.spawn(listen_unix(listener, server.clone()));
+    }
+
+    // deliver the spooled messages in the background
+    if server.spool.is_some() {
+        let server = server.clone();
+        listeners.spawn(async move {
+            if let Some(spool) = &server.spool {
+                spool.run(&server.config, &server.status_map).await;
+            }
+            Ok(())
+        });
+    }
+
+    // s6 readiness notification
+    s6_ready(args.ready_fd);
+
+    // run until a listener fails
+    while let Some(res) = listeners.join_next().await {
+        res??;
     }
     Ok(None)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Code and enhanced status code of the replies to a whole client session, odoo being
+    /// unreachable
+    async fn session(protocol: Protocol, input: &[u8]) -> Vec<String> {
+        static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+        let session = SESSIONS.fetch_add(1, Ordering::Relaxed);
+        let mut config = test_config(&format!("session-{}", session));
+        config.host = "127.0.0.1:1".to_string();
+        let recipients = Recipients::new("b@x.com a@x.com", "a@x.com lmtp:unix:/s").shared();
+        let server = Server::new(config, false, false, recipients).unwrap();
+        let (client, stream) = tokio::io::duplex(1 << 16);
+        let (mut reader, mut writer) = tokio::io::split(client);
+        writer.write_all(input).await.unwrap();
+        writer.shutdown().await.unwrap();
+        handle_client(stream, protocol, &server).await;
+        let mut replies = String::new();
+        reader.read_to_string(&mut replies).await.unwrap();
+        replies
+            .lines()
+            // last line of multiline replies
+            .filter(|line| line.as_bytes().get(3) != Some(&b'-'))
+            .map(|line| line.get(..9).unwrap_or(line).to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn command_sequence() {
+        let replies = session(
+            Protocol::Lmtp,
+            b"MAIL FROM:<s@y.com>\r\nLHLO x\r\nRCPT TO:<a@x.com>\r\nDATA\r\n\
+              MAIL FROM:<s@y.com>\r\nDATA\r\nRCPT TO:<c@x.com>\r\nMAIL FROM:<s@y.com>\r\n\
+              RCPT TO:<b@x.com>\r\nRSET\r\nDATA\r\nQUIT\r\n",
+        )
+        .await;
+        assert_eq!(
+            replies,
+            [
+                "220 local",
+                "503 5.5.1",
+                "250 BINAR",
+                "503 5.5.1",
+                "503 5.5.1",
+                "250 2.1.0",
+                "503 5.5.1",
+                "550 5.1.1",
+                "503 5.5.1",
+                "250 2.1.5",
+                "250 2.0.0",
+                "503 5.5.1",
+                "221 2.0.0"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn greetings() {
+        let replies = session(Protocol::Lmtp, b"EHLO x\r\nLHLO\r\nLHLO x\r\nQUIT\r\n").await;
+        assert_eq!(
+            replies,
+            [
+                "220 local",
+                "500 5.5.1",
+                "501 5.5.4",
+                "250 BINAR",
+                "221 2.0.0"
+            ]
+        );
+        let replies = session(Protocol::Smtp, b"LHLO x\r\nHELO x\r\nQUIT\r\n").await;
+        assert_eq!(replies, ["220 local", "500 5.5.1", "250 x", "221 2.0.0"]);
+    }
+
+    #[tokio::test]
+    async fn binarymime_requires_bdat() {
+        let replies = session(
+            Protocol::Lmtp,
+            b"LHLO x\r\nMAIL FROM:<s@y.com> BODY=BINARYMIME\r\nRCPT TO:<a@x.com>\r\n\
+              DATA\r\nQUIT\r\n",
+        )
+        .await;
+        assert_eq!(replies[4], "503 5.5.1");
+    }
+
+    #[tokio::test]
+    async fn commands_while_chunking() {
+        let replies = session(
+            Protocol::Lmtp,
+            b"LHLO x\r\nMAIL FROM:<s@y.com>\r\nRCPT TO:<a@x.com>\r\nBDAT 3\r\nabc\
+              MAIL FROM:<s@y.com>\r\nRCPT TO:<b@x.com>\r\nDATA\r\nRSET\r\nBDAT 1 LAST\r\nx\
+              QUIT\r\n",
+        )
+        .await;
+        assert_eq!(
+            replies[4..],
+            [
+                "250 2.0.0",
+                "503 5.5.1",
+                "503 5.5.1",
+                "503 5.5.1",
+                "250 2.0.0",
+                "503 5.5.1",
+                "221 2.0.0"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn bdat_size_overflow() {
+        let replies = session(
+            Protocol::Lmtp,
+            b"LHLO x\r\nMAIL FROM:<s@y.com>\r\nRCPT TO:<a@x.com>\r\nBDAT 5\r\nhello\
+              BDAT 18446744073709551615 LAST\r\nabc",
+        )
+        .await;
+        // the chunk is discarded until the end of the stream, which drops the pipelined replies
+        assert_eq!(replies, ["220 local"]);
+        let mut transaction = Transaction {
+            size: usize::MAX,
+            ..Default::default()
+        };
+        assert!(transaction.too_big(&test_config("overflow")));
+        transaction.size = 5;
+        assert!(!transaction.too_big(&test_config("overflow")));
+    }
+
+    #[tokio::test]
+    async fn reply_per_recipient() {
+        let input = b"MAIL FROM:<s@y.com>\r\nRCPT TO:<a@x.com>\r\nRCPT TO:<b@x.com>\r\n\
+                      DATA\r\nSubject: x\r\n\r\nbody\r\n.\r\nQUIT\r\n";
+        let replies = session(Protocol::Lmtp, &[b"LHLO x\r\n", &input[..]].concat()).await;
+        assert_eq!(
+            replies[5..],
+            ["354 Start", "421 4.4.1", "421 4.4.1", "221 2.0.0"]
+        );
+        let replies = session(Protocol::Smtp, &[b"EHLO x\r\n", &input[..]].concat()).await;
+        assert_eq!(replies[5..], ["354 Start", "421 4.4.1", "221 2.0.0"]);
+    }
+
+    /// Size and content read by read_data from the lines following DATA
+    async fn data(input: &[u8], max_size: usize) -> (Option<usize>, Vec<u8>) {
+        let mut data = Vec::new();
+        let size = read_data(&mut &input[..], &mut data, max_size)
+            .await
+            .unwrap();
+        (size, data)
+    }
+
+    #[tokio::test]
+    async fn data_transparency() {
+        let (size, content) = data(b"Subject: x\r\n\r\n..a\r\n..\r\na.\r\n.\r\nQUIT\r\n", 0).await;
+        assert_eq!(content, b"Subject: x\n\n.a\n.\na.\n");
+        assert_eq!(size, Some(content.len()));
+    }
+
+    #[tokio::test]
+    async fn data_bare_lf() {
+        let (size, content) = data(b"a\nb\r\nc\r\r\n.\n", 0).await;
+        assert_eq!(content, b"a\nb\nc\r\n");
+        assert_eq!(size, Some(7));
+    }
+
+    #[tokio::test]
+    async fn data_without_end() {
+        assert_eq!(data(b"a\r\n.", 0).await.0, None);
+    }
+
+    #[tokio::test]
+    async fn data_max_size() {
+        let (size, content) = data(b"aaaa\r\nbb\r\n.\r\n", 6).await;
+        assert_eq!(content, b"aaaa\n");
+        assert_eq!(size, Some(8));
+    }
+
+    #[tokio::test]
+    async fn data_long_lines() {
+        // read in pieces without unstuffing or ending a line in the middle
+        let mut input = vec![b'x'; MAX_LINE];
+        input.extend_from_slice(b".y\r\n");
+        input.extend_from_slice(&[b'z'; MAX_LINE]);
+        input.extend_from_slice(b"\r\n.\r\n");
+        let (size, content) = data(&input, 0).await;
+        let mut expected = vec![b'x'; MAX_LINE];
+        expected.extend_from_slice(b".y\n");
+        expected.extend_from_slice(&[b'z'; MAX_LINE]);
+        expected.push(b'\n');
+        assert_eq!(content, expected);
+        assert_eq!(size, Some(expected.len()));
+        // a line longer than max_size is not kept
+        let (size, content) = data(&input, 100).await;
+        assert!(content.is_empty());
+        assert_eq!(size, Some(expected.len()));
+    }
+
+    #[tokio::test]
+    async fn command_too_long() {
+        let mut input = vec![b'a'; 3 * MAX_LINE];
+        input.extend_from_slice(b"\r\nQUIT\r\n");
+        let mut stream = &input[..];
+        let mut command = Vec::new();
+        assert_eq!(read_command(&mut stream, &mut command).await.unwrap(), None);
+        command.clear();
+        assert_eq!(
+            read_command(&mut stream, &mut command).await.unwrap(),
+            Some(6)
+        );
+        assert_eq!(command, b"QUIT\r\n");
+    }
+
+    #[test]
+    fn normalize_line_endings() {
+        assert_eq!(normalize(b"a\r\nb\rc\n\r\r\n\r"), b"a\nb\rc\n\r\n\r");
+    }
+
+    #[tokio::test]
+    async fn bdat_chunks() {
+        let mut stream = &b"hello world\r\nQUIT\r\n"[..];
+        let mut data = Vec::new();
+        assert!(read_chunk(&mut stream, &mut data, 5, true).await.unwrap());
+        // a rejected chunk is read but not kept
+        assert!(read_chunk(&mut stream, &mut data, 8, false).await.unwrap());
+        assert_eq!(data, b"hello");
+        assert_eq!(stream, b"QUIT\r\n");
+        // end of the stream before the end of the chunk
+        assert!(!read_chunk(&mut stream, &mut data, 10, true).await.unwrap());
+    }
+
+    #[test]
+    fn smtp_single_reply() {
+        let rcpts = ["a@x.com".to_string(), "b@x.com".to_string()];
+        let ok = reply(250, "2.0.0", "<a@x.com> delivered");
+        let temp = reply(451, "4.4.1", "Can't reach odoo");
+        let perm = reply(550, "5.7.1", "Refused");
+        let accepted = reply(250, "2.0.0", "Message accepted");
+        assert_eq!(
+            smtp_reply("s@y.com", &rcpts, vec![ok.clone(), ok.clone()]),
+            accepted
+        );
+        // delivered to a recipient: the relay must not bounce or retry it
+        assert_eq!(
+            smtp_reply("s@y.com", &rcpts, vec![ok.clone(), perm.clone()]),
+            accepted
+        );
+        assert_eq!(
+            smtp_reply("s@y.com", &rcpts, vec![temp.clone(), ok]),
+            accepted
+        );
+        assert_eq!(
+            smtp_reply("s@y.com", &rcpts, vec![perm.clone(), temp.clone()]),
+            temp
+        );
+        assert_eq!(
+            smtp_reply("s@y.com", &rcpts, vec![perm.clone(), perm.clone()]),
+            perm
+        );
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(parse_path("TO:<a@b.c>", "to:"), Some("a@b.c"));
+        assert_eq!(parse_path("from: <a@b.c> SIZE=10", "FROM:"), Some("a@b.c"));
+        assert_eq!(parse_path("FROM:<>", "FROM:"), Some(""));
+        assert_eq!(parse_path("TO:<a@b.c>", "FROM:"), None);
+        assert_eq!(parse_path("TO", "TO:"), None);
+    }
+
+    #[test]
+    fn params() {
+        let arg = "FROM:<a@b.c> size=1234 BODY=8BITMIME SMTPUTF8";
+        assert_eq!(parse_param(arg, "SIZE"), Some("1234"));
+        assert_eq!(parse_param(arg, "body"), Some("8BITMIME"));
+        assert_eq!(parse_param(arg, "SMTPUTF8"), Some(""));
+        assert_eq!(parse_param(arg, "RET"), None);
+        // the path is not a parameter
+        assert_eq!(parse_param("FROM:<a@b.c>", "FROM:<a@b.c>"), None);
+    }
+}
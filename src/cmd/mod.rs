@@ -2,5 +2,8 @@ pub mod aliases;
 pub mod daemon;
 pub mod lmtp;
 pub mod pipe;
+pub mod policy;
+pub mod queue;
+pub mod socketmap;
 pub mod transport;
 pub mod webhook;
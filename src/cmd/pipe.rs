@@ -1,24 +1,74 @@
-use crate::{config::Config, errors::HttpError};
+use crate::{
+    args::Pipe,
+    config::Config,
+    delivery::{deliver, Delivery},
+    envelope::Envelope,
+    errors::{ExitError, EX_TEMPFAIL},
+    status::StatusMap,
+};
 use anyhow::{Error, Result};
-use std::io::{self, Read};
-use ureq::post;
+use tokio::io::{self, AsyncReadExt};
 
-pub fn cmd(config: &Config) -> Result<Option<String>> {
-    let mut buffer = String::new();
-    io::stdin().read_to_string(&mut buffer)?;
-    // sync post request the encoded email coming from stdin
-    let url = format!("https://{}/mail_delivery/pipe", &config.host);
-    let resp = post(&url)
-        .set("X-Mail-Token", &config.token)
-        .set("Content-Type", "text/plain")
-        .send_string(&buffer);
-
-    if resp.ok() {
-        let text = resp.into_string()?;
-        Ok(Some(text))
+pub async fn cmd(config: &Config, args: Pipe) -> Result<Option<String>> {
+    let status_map = match StatusMap::new(&config.status_map) {
+        Ok(status_map) => status_map,
+        Err(e) => {
+            return Err(Error::new(ExitError::new(
+                EX_TEMPFAIL,
+                &format!("4.3.5 {}", e),
+            )))
+        }
+    };
+    // mail is not required to be valid utf-8
+    let mut buffer = Vec::new();
+    if let Err(e) = io::stdin().read_to_end(&mut buffer).await {
+        return Err(Error::new(ExitError::new(
+            EX_TEMPFAIL,
+            &format!("4.3.0 Can't read the message: {}", e),
+        )));
+    }
+    // post request the encoded email coming from stdin, once per recipient with its envelope
+    let deliveries = if args.recipients.is_empty() {
+        vec![deliver(config, buffer).await]
     } else {
-        let code = resp.status();
-        let details = resp.into_string()?;
-        Err(Error::new(HttpError::new(code, &details)))
+        let sender = args.sender.as_deref().unwrap_or("");
+        let mut deliveries = Vec::new();
+        for (i, recipient) in args.recipients.iter().enumerate() {
+            let envelope = Envelope {
+                sender,
+                recipient,
+                original_recipient: args.original_recipient.get(i).map(String::as_str),
+                client_address: args.client_address.as_deref(),
+            };
+            let content = [envelope.headers("\n").as_bytes(), &buffer].concat();
+            deliveries.push(deliver(config, content).await);
+        }
+        deliveries
+    };
+
+    // postfix can only defer or bounce the whole message: a temporary failure for any recipient
+    // defers it, even if it may be duplicated for the other recipients
+    let mut failures = deliveries
+        .iter()
+        .filter(|delivery| !matches!(delivery, Delivery::Delivered(_)))
+        .map(|delivery| (status_map.map(delivery).1, delivery))
+        .collect::<Vec<_>>();
+    failures.sort_by_key(|(status, _)| !status.starts_with('4'));
+    match failures.first() {
+        // postfix uses the enhanced status code at the beginning of the output
+        Some((status, delivery)) => Err(Error::new(ExitError::from_status(
+            status,
+            &format!("{} {}", status, delivery),
+        ))),
+        None => Ok(Some(
+            deliveries
+                .iter()
+                .map(|delivery| match delivery {
+                    Delivery::Delivered(text) => text.as_str(),
+                    _ => "",
+                })
+                .collect::<Vec<&str>>()
+                .join(" "),
+        )),
     }
 }
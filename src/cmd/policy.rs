@@ -0,0 +1,118 @@
+use crate::{recipients::SharedRecipients, utils::Listener};
+use anyhow::Result;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+// maximum length of a request, postfix sends less than 2000 bytes
+const MAX_REQUEST: usize = 10000;
+
+/// Decide on a policy request: reject recipients of odoo domains unknown by odoo
+fn check(request: &[(String, String)], recipients: &SharedRecipients) -> String {
+    let recipient = request
+        .iter()
+        .find(|(name, _)| name == "recipient")
+        .map(|(_, value)| value.as_str())
+        .unwrap_or("");
+    let recipients = recipients.read().unwrap();
+    // nothing to check before RCPT or before the aliases are loaded
+    if recipient.is_empty() || !recipients.loaded() {
+        return "DUNNO".to_string();
+    }
+    if recipients.manages(recipient) && !recipients.contains(recipient) {
+        format!(
+            "REJECT 5.1.1 <{}>: Recipient address rejected: unknown odoo address",
+            recipient
+        )
+    } else {
+        "DUNNO".to_string()
+    }
+}
+
+/// Answer the policy requests of a postfix connection until it is closed (access policy
+/// delegation protocol: `name=value` lines ended by an empty line)
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(stream: S, recipients: SharedRecipients) {
+    let mut stream = BufReader::new(stream);
+    let mut request = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = String::new();
+        let mut limited = (&mut stream).take((MAX_REQUEST - size) as u64);
+        match limited.read_line(&mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => size += n,
+        }
+        // request too long or truncated
+        if !line.ends_with('\n') {
+            return;
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if !line.is_empty() {
+            if let Some((name, value)) = line.split_once('=') {
+                request.push((name.to_string(), value.to_string()));
+            }
+            continue;
+        }
+        let action = check(&request, &recipients);
+        request.clear();
+        size = 0;
+        let reply = format!("action={}\n\n", action);
+        if stream.get_mut().write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Serve the policy requests from the shared recipient set, on a unix socket or a tcp address
+pub async fn serve(address: &str, recipients: SharedRecipients) -> Result<()> {
+    let listener = Listener::bind(address).await?;
+    println!("policy serving at {}", address);
+    loop {
+        let stream = listener.accept().await?;
+        tokio::spawn(handle_client(stream, recipients.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recipients::Recipients;
+    use tokio::io::duplex;
+
+    fn request(recipient: &str) -> Vec<(String, String)> {
+        [("request", "smtpd_access_policy"), ("recipient", recipient)]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn checks() {
+        let recipients = Recipients::default().shared();
+        assert_eq!(check(&request("c@x.com"), &recipients), "DUNNO");
+        let recipients = Recipients::new("b@x.com a@x.com", "a@x.com lmtp:unix:/s").shared();
+        let check = |recipient| check(&request(recipient), &recipients);
+        assert!(check("c@x.com").starts_with("REJECT 5.1.1 <c@x.com>"));
+        assert!(check("C@X.com").starts_with("REJECT "));
+        assert_eq!(check("a@x.com"), "DUNNO");
+        assert_eq!(check("B@x.com"), "DUNNO");
+        // other domains and requests before RCPT
+        assert_eq!(check("c@y.com"), "DUNNO");
+        assert_eq!(check(""), "DUNNO");
+        assert_eq!(super::check(&[], &recipients), "DUNNO");
+    }
+
+    #[tokio::test]
+    async fn request_too_long() {
+        let recipients = Recipients::new("b@x.com a@x.com", "a@x.com lmtp:unix:/s").shared();
+        let (client, server) = duplex(1 << 16);
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let mut input = b"recipient=c@x.com\n\n".to_vec();
+        input.extend(b"name=".repeat(MAX_REQUEST / 5));
+        input.extend(b"\n\n");
+        writer.write_all(&input).await.unwrap();
+        handle_client(server, recipients).await;
+        let mut output = String::new();
+        reader.read_to_string(&mut output).await.unwrap();
+        assert!(output.starts_with("action=REJECT 5.1.1 <c@x.com>"));
+        assert_eq!(output.matches("action=").count(), 1);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::{
+    args::{Queue, QueueCommand},
+    config::Config,
+    spool::{now, Entry, Spool},
+};
+use anyhow::{anyhow, Result};
+use std::io::{self, Write};
+
+/// Human readable age (`3d4h`, `2h5m`, `12m`)
+fn age(created: u64) -> String {
+    let secs = now().saturating_sub(created);
+    let (days, hours, minutes) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    if days > 0 {
+        format!("{}d{}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h{}m", hours, minutes)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+/// Delivery state of a message
+fn state(entry: &Entry) -> String {
+    let wait = entry.next_attempt.saturating_sub(now());
+    if entry.held {
+        "held".to_string()
+    } else if wait == 0 {
+        "due".to_string()
+    } else {
+        format!("retry in {}m", wait.div_ceil(60))
+    }
+}
+
+/// Change the metadata of a message while holding its lock, waiting for a delivery attempt in
+/// progress
+async fn update(spool: &Spool, id: &str, change: impl FnOnce(&mut Entry)) -> Result<()> {
+    let _lock = spool.lock(id, true).await?;
+    let mut entry = spool.load(id).await?;
+    change(&mut entry);
+    spool.save(id, &entry).await
+}
+
+/// Make a message due for the next run of the delivery worker
+async fn retry(spool: &Spool, id: &str) -> Result<()> {
+    update(spool, id, |entry| {
+        entry.held = false;
+        entry.next_attempt = now();
+    })
+    .await
+}
+
+pub async fn cmd(config: &Config, args: Queue) -> Result<Option<String>> {
+    let spool = match &config.spool {
+        Some(spool) => Spool::new(spool)?,
+        None => return Err(anyhow!("No spool configured")),
+    };
+    match args.subcmd {
+        QueueCommand::List(_) => {
+            let entries = spool.entries().await?;
+            for (id, entry) in &entries {
+                println!(
+                    "{}  {:>9}  {:>6}  {:>3} tries  {}",
+                    id,
+                    spool.size(id).await.unwrap_or(0),
+                    age(entry.created),
+                    entry.attempts,
+                    state(entry)
+                );
+                println!("    from <{}> to <{}>", entry.sender, entry.recipient);
+                if !entry.last_error.is_empty() {
+                    println!("    ({})", entry.last_error);
+                }
+            }
+            println!("-- {} message(s)", entries.len());
+        }
+        QueueCommand::Show(args) => {
+            let entry = spool.load(&args.id).await?;
+            println!("Id: {}", args.id);
+            println!("Sender: {}", entry.sender);
+            println!("Recipient: {}", entry.recipient);
+            println!("Size: {}", spool.size(&args.id).await?);
+            println!("Age: {}", age(entry.created));
+            println!("Attempts: {}", entry.attempts);
+            println!("State: {}", state(&entry));
+            println!("Last error: {}", entry.last_error);
+            println!();
+            // content is not required to be valid utf-8
+            io::stdout().write_all(&spool.content(&args.id).await?)?;
+        }
+        QueueCommand::Retry(args) => match (args.id, args.all) {
+            (Some(id), false) => {
+                retry(&spool, &id).await?;
+                println!("{} will be retried", id);
+            }
+            (None, true) => {
+                // held messages must be released one by one
+                let mut count = 0;
+                for (id, entry) in spool.entries().await? {
+                    if !entry.held {
+                        // delivered or deleted in the meantime
+                        match retry(&spool, &id).await {
+                            Ok(()) => count += 1,
+                            Err(e) => eprintln!("{}: {}", id, e),
+                        }
+                    }
+                }
+                println!("{} message(s) will be retried", count);
+            }
+            _ => return Err(anyhow!("Give either a message id or --all")),
+        },
+        QueueCommand::Delete(args) => {
+            let _lock = spool.lock(&args.id, true).await?;
+            spool.load(&args.id).await?;
+            spool.remove(&args.id).await?;
+            println!("{} deleted", args.id);
+        }
+        QueueCommand::Hold(args) => {
+            update(&spool, &args.id, |entry| entry.held = true).await?;
+            println!("{} held", args.id);
+        }
+        QueueCommand::Release(args) => {
+            retry(&spool, &args.id).await?;
+            println!("{} released", args.id);
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,125 @@
+use crate::{config::Config, recipients::SharedRecipients, utils::Listener};
+use anyhow::Result;
+use std::{io, sync::Arc};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+
+// maximum length of a request, postfix addresses are much shorter
+const MAX_REQUEST: usize = 10000;
+
+/// Read a netstring (`<length>:<data>,`), None at the end of the stream
+async fn read_netstring<R: AsyncBufRead + Unpin>(stream: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length = Vec::new();
+    let mut limited = stream.take(MAX_REQUEST as u64);
+    if limited.read_until(b':', &mut length).await? == 0 {
+        return Ok(None);
+    }
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid netstring");
+    let length = std::str::from_utf8(length.strip_suffix(b":").ok_or_else(invalid)?)
+        .ok()
+        .and_then(|length| length.parse::<usize>().ok())
+        .filter(|length| *length <= MAX_REQUEST)
+        .ok_or_else(invalid)?;
+    // data and the trailing comma
+    let mut data = vec![0; length + 1];
+    stream.read_exact(&mut data).await?;
+    if data.pop() != Some(b',') {
+        return Err(invalid());
+    }
+    Ok(Some(data))
+}
+
+/// Encode a reply as a netstring
+fn netstring(reply: &str) -> String {
+    format!("{}:{},", reply.len(), reply)
+}
+
+/// Answer a `<map> <key>` lookup (`virtual` alias -> account, `transport` account -> nexthop)
+fn lookup(request: &str, nexthop: &str, recipients: &SharedRecipients) -> String {
+    let (map, key) = match request.split_once(' ') {
+        Some((map, key)) => (map, key),
+        None => return "PERM invalid request".to_string(),
+    };
+    let recipients = recipients.read().unwrap();
+    if !recipients.loaded() {
+        return "TEMP odoo aliases not loaded".to_string();
+    }
+    let value = match map {
+        "virtual" => recipients.account(key).map(str::to_string),
+        "transport" => recipients.is_account(key).then(|| nexthop.to_string()),
+        _ => return format!("PERM unknown map {}", map),
+    };
+    match value {
+        Some(value) => format!("OK {}", value),
+        None => "NOTFOUND ".to_string(),
+    }
+}
+
+/// Answer the lookups of a postfix connection until it is closed
+async fn handle_client<S>(stream: S, nexthop: Arc<String>, recipients: SharedRecipients)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    while let Ok(Some(request)) = read_netstring(&mut stream).await {
+        let reply = lookup(&String::from_utf8_lossy(&request), &nexthop, &recipients);
+        if stream
+            .get_mut()
+            .write_all(netstring(&reply).as_bytes())
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Serve the socketmap lookups from the shared recipient set, on a unix socket or a tcp address
+pub async fn serve(config: &Config, address: &str, recipients: SharedRecipients) -> Result<()> {
+    let nexthop = Arc::new(config.nexthop());
+    let listener = Listener::bind(address).await?;
+    println!("socketmap serving at {}", address);
+    loop {
+        let stream = listener.accept().await?;
+        tokio::spawn(handle_client(stream, nexthop.clone(), recipients.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recipients::Recipients;
+
+    #[tokio::test]
+    async fn netstrings() {
+        let mut stream = &b"15:virtual a@x.com,0:,"[..];
+        assert_eq!(
+            read_netstring(&mut stream).await.unwrap().unwrap(),
+            b"virtual a@x.com"
+        );
+        assert_eq!(read_netstring(&mut stream).await.unwrap().unwrap(), b"");
+        assert_eq!(read_netstring(&mut stream).await.unwrap(), None);
+        for invalid in [&b"3:abc;"[..], b"x:a,", b"99999:a,", b"5:abc"] {
+            assert!(read_netstring(&mut &invalid[..]).await.is_err());
+        }
+        // length without separator
+        let long = vec![b'1'; MAX_REQUEST * 2];
+        assert!(read_netstring(&mut &long[..]).await.is_err());
+        assert_eq!(netstring("OK a@x.com"), "10:OK a@x.com,");
+    }
+
+    #[test]
+    fn lookups() {
+        let recipients = Recipients::default().shared();
+        assert!(lookup("virtual b@x.com", "lmtp:unix:/s", &recipients).starts_with("TEMP "));
+        let recipients = Recipients::new("b@x.com a@x.com", "a@x.com lmtp:unix:/s").shared();
+        let lookup = |request| lookup(request, "lmtp:unix:/s", &recipients);
+        assert_eq!(lookup("virtual B@x.com"), "OK a@x.com");
+        assert_eq!(lookup("virtual c@x.com"), "NOTFOUND ");
+        assert_eq!(lookup("transport a@x.com"), "OK lmtp:unix:/s");
+        assert_eq!(lookup("transport b@x.com"), "NOTFOUND ");
+        assert!(lookup("canonical a@x.com").starts_with("PERM "));
+        assert!(lookup("virtual").starts_with("PERM "));
+    }
+}
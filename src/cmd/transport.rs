@@ -1,10 +1,50 @@
-use crate::{config::Config, utils::MapType};
+use crate::{
+    args::Transport,
+    config::Config,
+    recipients::Recipients,
+    utils::{MapType, MapsLock},
+};
 use anyhow::Result;
 
-pub fn cmd(config: &Config) -> Result<Option<String>> {
-    if let Ok(data) = MapType::Transport.get(config) {
-        let data = format!("{} lmtp:unix:{}", data, &config.socket);
-        MapType::Transport.write(config, &data.as_bytes())?;
+/// Transport map of the odoo addresses received from odoo, one `address nexthop` entry per
+/// address like the maps written by the webhook
+pub fn with_nexthop(config: &Config, data: &str) -> String {
+    data.split_whitespace()
+        .map(|address| format!("{} {}", address, config.nexthop()))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Get transport from odoo with the nexthop of odoo addresses
+async fn get(config: &Config) -> Result<String> {
+    let data = MapType::Transport.get(config).await?;
+    Ok(with_nexthop(config, &data))
+}
+
+/// Get transport from odoo and write the map, returning its content
+pub async fn update(config: &Config, force: bool) -> Result<Option<String>> {
+    if let Ok(data) = get(config).await {
+        let lock = MapsLock::acquire(config).await?;
+        let recipients = Recipients::default().shared();
+        MapType::Transport
+            .write(config, &data, force, &recipients, &lock)
+            .await?;
+        return Ok(Some(data));
+    }
+    Ok(None)
+}
+
+pub async fn cmd(config: &Config, args: Transport) -> Result<Option<String>> {
+    if args.dry_run {
+        let data = get(config).await?;
+        print!(
+            "{}",
+            MapType::Transport
+                .check(config, &data, args.force, &Recipients::default().shared())
+                .await?
+        );
+        return Ok(None);
     }
+    update(config, args.force).await?;
     Ok(None)
 }
@@ -1,21 +1,169 @@
 use crate::{
     args::Webhook,
+    cmd::transport::with_nexthop,
     config::Config,
-    utils::{s6_ready, MapType},
+    errors::MapError,
+    recipients::{Recipients, SharedRecipients},
+    utils::{s6_ready, shutdown, MapType, MapsLock},
 };
-use anyhow::Result;
-use std::collections::hash_map::HashMap;
-use tiny_http::{Header, Method, Response, Server};
+use anyhow::{Error, Result};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::HeaderMap,
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use std::{collections::hash_map::HashMap, sync::Arc};
+use tokio::net::TcpListener;
+
+fn get_header<'a>(headers: &'a HeaderMap, key: &'static str) -> Option<&'a str> {
+    headers.get(key).and_then(|v| v.to_str().ok())
+}
 
-fn get_header<'a>(headers: &'a [Header], key: &'static str) -> Option<&'a str> {
-    headers
-        .iter()
-        .find(|&header| header.field.equiv(key))
-        .and_then(|v| Some(v.value.as_str()))
+fn respond(status: StatusCode, body: &str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_string())));
+    *response.status_mut() = status;
+    response
 }
 
-pub fn cmd(config: Config, args: Webhook, verbose: bool) -> Result<Option<String>> {
-    let server = Server::http(format!("0.0.0.0:{}", args.port)).unwrap();
+pub async fn cmd(config: Config, args: Webhook, verbose: bool) -> Result<Option<String>> {
+    tokio::select! {
+        res = serve(config, args, verbose, Recipients::default().shared()) => res,
+        _ = shutdown() => Ok(None),
+    }
+}
+
+/// State shared by all the requests
+struct Server {
+    config: Config,
+    prefix: String,
+    verbose: bool,
+    recipients: SharedRecipients,
+}
+
+/// Aliases and transport maps of the yaml sent by odoo (local parts of the aliases by account)
+pub fn maps(config: &Config, data: &str) -> Result<(String, String)> {
+    let mut aliases_map = Vec::new();
+    let mut accounts = Vec::new();
+    match serde_yaml::from_str::<HashMap<String, Vec<String>>>(data) {
+        Ok(data) => {
+            for (account, aliases) in data {
+                let v: Vec<&str> = account.split('@').collect();
+                let domain = match v.get(1) {
+                    Some(domain) => domain,
+                    None => "",
+                };
+                aliases_map.push(String::from(
+                    &aliases
+                        .iter()
+                        .map(|v| format!("{}@{} {}", v, domain, account))
+                        .collect::<Vec<String>>()
+                        .join("\n"),
+                ));
+                accounts.push(account);
+            }
+        }
+        Err(e) => return Err(Error::new(MapError::new(&format!("invalid yaml: {}", e)))),
+    }
+    // same transport entries as the ones received from odoo
+    Ok((
+        aliases_map.join("\n"),
+        with_nexthop(config, &accounts.join(" ")),
+    ))
+}
+
+/// Update the maps and the shared recipients from the yaml body of the request, unless it is
+/// invalid or removes too many entries and `force` is not in the query string
+async fn update(server: &Server, request: Request<Incoming>) -> Result<()> {
+    let config = &server.config;
+    let force = request
+        .uri()
+        .query()
+        .is_some_and(|query| query.split('&').any(|param| param == "force"));
+    let data = request.into_body().collect().await?.to_bytes();
+    // serialize the yaml
+    let (aliases_map, transport_map) = maps(config, &String::from_utf8_lossy(&data))?;
+    // requests are handled concurrently but the maps are updated one at a time
+    let lock = MapsLock::acquire(config).await?;
+    // check both maps before replacing any of them
+    let recipients = &server.recipients;
+    MapType::Aliases
+        .check(config, &aliases_map, force, recipients)
+        .await?;
+    MapType::Transport
+        .check(config, &transport_map, force, recipients)
+        .await?;
+    // write aliases map
+    MapType::Aliases
+        .write(config, &aliases_map, force, recipients, &lock)
+        .await?;
+    // write transport map
+    MapType::Transport
+        .write(config, &transport_map, force, recipients, &lock)
+        .await?;
+    // refresh recipients used by lmtp
+    *server.recipients.write().unwrap() = Recipients::new(&aliases_map, &transport_map);
+    Ok(())
+}
+
+async fn handle(
+    server: Arc<Server>,
+    request: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    if server.verbose {
+        println!(
+            "received request! method: {:?}, url: {:?}, headers: {:?}",
+            request.method(),
+            request.uri(),
+            request.headers()
+        );
+    }
+    // check that it's a post request with configured prefix
+    if request.method() != Method::POST || request.uri().path() != server.prefix {
+        // not found
+        return Ok(respond(StatusCode::NOT_FOUND, ""));
+    }
+    // check that we have yaml body
+    match get_header(request.headers(), "content-type") {
+        Some("application/yaml") => (),
+        _ => {
+            eprintln!("error no encoded yaml");
+            return Ok(respond(StatusCode::UNSUPPORTED_MEDIA_TYPE, ""));
+        }
+    }
+    // check the token
+    match get_header(request.headers(), "x-mail-token") {
+        // authorized
+        Some(header) if server.config.token == header => match update(&server, request).await {
+            Ok(()) => Ok(respond(StatusCode::OK, "")),
+            Err(e) => {
+                eprintln!("error {}", e);
+                // tell the caller why the maps were not replaced
+                match e.downcast_ref::<MapError>() {
+                    Some(e) => Ok(respond(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        &format!("{}\n", e),
+                    )),
+                    None => Ok(respond(StatusCode::INTERNAL_SERVER_ERROR, "")),
+                }
+            }
+        },
+        // unauthorized
+        _ => Ok(respond(StatusCode::UNAUTHORIZED, "")),
+    }
+}
+
+/// Serve the webhook, updating the maps and the shared recipients on each call
+pub async fn serve(
+    config: Config,
+    args: Webhook,
+    verbose: bool,
+    recipients: SharedRecipients,
+) -> Result<Option<String>> {
+    let listener = TcpListener::bind(("0.0.0.0", args.port)).await?;
 
     println!(
         "webhook serving at http://0.0.0.0:{}{}",
@@ -25,73 +173,20 @@ pub fn cmd(config: Config, args: Webhook, verbose: bool) -> Result<Option<String
     // s6 readiness notification
     s6_ready(args.ready_fd);
 
-    for mut request in server.incoming_requests() {
-        if verbose {
-            println!(
-                "received request! method: {:?}, url: {:?}, headers: {:?}",
-                request.method(),
-                request.url(),
-                request.headers()
-            );
-        }
-        // check that it's a post request with configured prefix
-        if request.method() == &Method::Post && request.url() == args.prefix {
-            // check that we have yaml body
-            match get_header(request.headers(), &"content-type") {
-                Some("application/yaml") => (),
-                _ => {
-                    eprintln!("error {}", "no encoded yaml");
-                    continue;
-                }
-            }
-            // check the token
-            match get_header(request.headers(), &"x-mail-token") {
-                // authorized
-                Some(header) if config.token == header => {
-                    let mut data = String::new();
-                    request.as_reader().read_to_string(&mut data)?;
-                    // serialize the yaml
-                    let mut aliases_map = Vec::new();
-                    let mut transport_map = Vec::new();
-                    match serde_yaml::from_str::<HashMap<String, Vec<String>>>(&data) {
-                        Ok(data) => {
-                            for (account, aliases) in data {
-                                let v: Vec<&str> = account.split('@').collect();
-                                let domain = match v.get(1) {
-                                    Some(domain) => domain,
-                                    None => "",
-                                };
-                                aliases_map.push(String::from(
-                                    &aliases
-                                        .iter()
-                                        .map(|v| format!("{}@{} {}", v, domain, account))
-                                        .collect::<Vec<String>>()
-                                        .join("\n"),
-                                ));
-                                transport_map
-                                    .push(format!("{} lmtp:unix:{}", account, &config.socket));
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("error {}", e);
-                        }
-                    }
-                    // write aliases map
-                    MapType::Aliases.write(&config, aliases_map.join("\n").as_bytes())?;
-                    // write transport map
-                    MapType::Transport.write(&config, transport_map.join("\n").as_bytes())?;
-                    request.respond(Response::empty(200))?;
-                }
-                // unauthorized
-                _ => {
-                    request.respond(Response::empty(401))?;
-                }
-            };
-        // not found
-        } else {
-            request.respond(Response::empty(404))?;
-        }
+    let server = Arc::new(Server {
+        config,
+        prefix: args.prefix,
+        verbose,
+        recipients,
+    });
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let server = server.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle(server.clone(), request));
+            let _ = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await;
+        });
     }
-
-    Ok(None)
 }
@@ -1,4 +1,5 @@
-use anyhow::{Context, Result};
+use crate::{format::MapFormat, status::StatusRule};
+use anyhow::{anyhow, Context, Result};
 use serde::Deserialize;
 use std::fs::OpenOptions;
 
@@ -10,8 +11,99 @@ pub struct Config {
     pub aliases: String,
     #[serde(default = "default_transport")]
     pub transport: String,
+    // format of the aliases and transport maps
+    #[serde(default)]
+    pub map_format: MapFormat,
+    // commands run after writing the maps instead of the compile command of the map format
+    #[serde(default)]
+    pub hooks: Hooks,
     #[serde(default = "default_socket")]
     pub socket: String,
+    // maximum message size in bytes advertised with SIZE (0 for no limit)
+    #[serde(default = "default_max_size")]
+    pub max_size: usize,
+    // additional tcp listeners for lmtp
+    #[serde(default)]
+    pub listen: Vec<Listener>,
+    // nexthop written in the transport map (lmtp:unix:<socket> by default)
+    pub nexthop: Option<String>,
+    // maximum number of concurrent lmtp sessions
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: usize,
+    // maximum number of concurrent deliveries to odoo
+    #[serde(default = "default_max_deliveries")]
+    pub max_deliveries: usize,
+    // maximum duration in seconds of a request to odoo, mapped like a connection error
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // directory keeping the messages accepted while odoo is unavailable, retried in the
+    // background (messages are rejected with 421 without it)
+    pub spool: Option<String>,
+    // smtp replies to odoo errors, tried before the default ones
+    #[serde(default)]
+    pub status_map: Vec<StatusRule>,
+    // maximum percentage of the entries of a map that an update can remove without being forced
+    #[serde(default = "default_max_shrink")]
+    pub max_shrink: usize,
+    // postfix socketmap server answering virtual and transport lookups (unix socket path or
+    // tcp address), daemon mode only
+    pub socketmap: Option<String>,
+    // postfix policy service rejecting unknown addresses of odoo domains (unix socket path or
+    // tcp address), daemon mode only
+    pub policy: Option<String>,
+    // interval in seconds between two refreshes of the maps from odoo in daemon and lmtp modes
+    // (0 to disable)
+    #[serde(default)]
+    pub refresh: u64,
+    // log an alert when the maps couldn't be refreshed for this many seconds
+    #[serde(default = "default_refresh_alert")]
+    pub refresh_alert: u64,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct Hooks {
+    // shell commands, {path} being replaced by the path of the map
+    pub aliases: Option<Vec<String>>,
+    pub transport: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Listener {
+    pub address: String,
+    #[serde(default)]
+    pub protocol: Protocol,
+    pub tls: Option<Tls>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    // one reply per recipient after the message content (RFC 2033)
+    #[default]
+    Lmtp,
+    // a single reply after the message content, to receive mail from a relay
+    Smtp,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Tls {
+    pub cert: String,
+    pub key: String,
+    // require client certificates signed by this ca
+    pub client_ca: Option<String>,
+    // only accept client certificates with these sha256 fingerprints
+    #[serde(default)]
+    pub allowed_clients: Vec<String>,
+}
+
+impl Config {
+    /// Nexthop of odoo addresses in the transport map
+    pub fn nexthop(&self) -> String {
+        match &self.nexthop {
+            Some(nexthop) => nexthop.clone(),
+            None => format!("lmtp:unix:{}", &self.socket),
+        }
+    }
 }
 
 fn default_aliases() -> String {
@@ -26,14 +118,58 @@ fn default_socket() -> String {
     "/var/spool/postfix/private/odoo-lmtp".to_string()
 }
 
+fn default_max_size() -> usize {
+    25 * 1024 * 1024
+}
+
+fn default_max_sessions() -> usize {
+    50
+}
+
+fn default_max_deliveries() -> usize {
+    10
+}
+
+fn default_timeout() -> u64 {
+    120
+}
+
+fn default_max_shrink() -> usize {
+    50
+}
+
+fn default_refresh_alert() -> u64 {
+    3600
+}
+
 pub fn get_config(config: &str) -> Result<Config> {
     // open configuration file
     let file = OpenOptions::new()
         .read(true)
-        .open(&config)
+        .open(config)
         .with_context(|| format!("Can't open {}", &config))?;
     // deserialize configuration
     let config: Config =
         serde_yaml::from_reader(file).with_context(|| format!("Can't read {}", &config))?;
+    // the default nexthop is a postfix transport, not a sendmail mailer:host
+    if config.map_format == MapFormat::Sendmail && config.nexthop.is_none() {
+        return Err(anyhow!(
+            "nexthop (mailer:host) is required with map_format sendmail"
+        ));
+    }
     Ok(config)
 }
+
+/// Configuration writing the maps in an empty temporary directory, without hooks
+#[cfg(test)]
+pub fn test_config(name: &str) -> Config {
+    let dir = std::env::temp_dir().join(format!("odoo-mailer-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    serde_yaml::from_str(&format!(
+        "host: localhost\ntoken: t\naliases: {0}/aliases\ntransport: {0}/transport\n\
+         socket: {0}/lmtp\nhooks: {{aliases: [], transport: []}}\n",
+        dir.display()
+    ))
+    .unwrap()
+}
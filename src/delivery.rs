@@ -0,0 +1,50 @@
+use crate::{config::Config, utils::client};
+use std::{fmt, time::Duration};
+
+/// Outcome of posting a message to odoo
+pub enum Delivery {
+    // odoo accepted the message and answered with this text
+    Delivered(String),
+    // odoo answered with an error status and the first line of its reply
+    Rejected(u16, String),
+    // odoo couldn't be reached
+    Failed(reqwest::Error),
+}
+
+impl fmt::Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Delivery::Delivered(_) => write!(f, "delivered"),
+            Delivery::Rejected(code, msg) => write!(f, "{} ({})", msg, code),
+            Delivery::Failed(e) => write!(f, "Can't reach odoo: {}", e),
+        }
+    }
+}
+
+/// Post a message (with its envelope headers) to odoo
+pub async fn deliver(config: &Config, content: Vec<u8>) -> Delivery {
+    let url = format!("https://{}/mail_delivery/pipe", &config.host);
+    let resp = client()
+        .post(&url)
+        .header("X-Mail-Token", &config.token)
+        .header("Content-Type", "text/plain")
+        .timeout(Duration::from_secs(config.timeout))
+        .body(content)
+        .send()
+        .await;
+
+    match resp {
+        Ok(resp) if resp.status().is_success() => {
+            Delivery::Delivered(resp.text().await.unwrap_or_default())
+        }
+        Ok(resp) => {
+            let code = resp.status().as_u16();
+            let msg = match resp.text().await {
+                Ok(msg) => msg.lines().next().unwrap_or("").trim().to_string(),
+                _ => "".to_string(),
+            };
+            Delivery::Rejected(code, msg)
+        }
+        Err(e) => Delivery::Failed(e),
+    }
+}
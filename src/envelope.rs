@@ -0,0 +1,29 @@
+/// Envelope of a message for a single recipient
+pub struct Envelope<'a> {
+    // reverse path, empty for bounces (MAIL FROM:<>)
+    pub sender: &'a str,
+    pub recipient: &'a str,
+    pub original_recipient: Option<&'a str>,
+    // address of the client which sent the message
+    pub client_address: Option<&'a str>,
+}
+
+impl<'a> Envelope<'a> {
+    /// Trace headers prepended to the message so that odoo routes it on the envelope
+    /// recipient even when it doesn't appear in To or Cc (bcc, mailing lists)
+    pub fn headers(&self, eol: &str) -> String {
+        let mut headers = format!(
+            "Return-Path: <{}>{eol}Delivered-To: {}{eol}",
+            self.sender,
+            self.recipient,
+            eol = eol
+        );
+        if let Some(original_recipient) = self.original_recipient {
+            headers.push_str(&format!("X-Original-To: {}{}", original_recipient, eol));
+        }
+        if let Some(client_address) = self.client_address {
+            headers.push_str(&format!("X-Originating-IP: [{}]{}", client_address, eol));
+        }
+        headers
+    }
+}
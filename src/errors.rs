@@ -31,3 +31,92 @@ impl fmt::Display for HttpError {
         write!(f, "{} {}", self.code, self.details)
     }
 }
+
+// sysexits(3) exit statuses understood by postfix pipe(8)
+pub const EX_DATAERR: i32 = 65;
+pub const EX_NOUSER: i32 = 67;
+pub const EX_UNAVAILABLE: i32 = 69;
+pub const EX_TEMPFAIL: i32 = 75;
+
+/// Error terminating the process with a specific exit status
+#[derive(Debug)]
+pub struct ExitError {
+    pub status: i32,
+    details: String,
+}
+
+impl ExitError {
+    pub fn new(status: i32, details: &str) -> ExitError {
+        ExitError {
+            status,
+            details: details.to_string(),
+        }
+    }
+
+    /// Exit status of a failed delivery from its enhanced status code (RFC 3463)
+    pub fn from_status(status: &str, details: &str) -> ExitError {
+        let status = match status.split('.').take(2).collect::<Vec<&str>>()[..] {
+            ["4", _] => EX_TEMPFAIL,
+            // bad destination mailbox
+            ["5", "1"] => EX_NOUSER,
+            // bad message content
+            ["5", "6"] => EX_DATAERR,
+            _ => EX_UNAVAILABLE,
+        };
+        ExitError::new(status, details)
+    }
+}
+
+impl std::error::Error for ExitError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+/// Map update refused because the new content looks wrong
+#[derive(Debug)]
+pub struct MapError {
+    details: String,
+}
+
+impl MapError {
+    pub fn new(details: &str) -> MapError {
+        MapError {
+            details: details.to_string(),
+        }
+    }
+}
+
+impl std::error::Error for MapError {
+    fn description(&self) -> &str {
+        &self.details
+    }
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_status() {
+        let status = |status| ExitError::from_status(status, "").status;
+        assert_eq!(status("4.4.1"), EX_TEMPFAIL);
+        assert_eq!(status("4.1.1"), EX_TEMPFAIL);
+        assert_eq!(status("5.1.1"), EX_NOUSER);
+        assert_eq!(status("5.6.0"), EX_DATAERR);
+        assert_eq!(status("5.7.1"), EX_UNAVAILABLE);
+        assert_eq!(status("invalid"), EX_UNAVAILABLE);
+    }
+}
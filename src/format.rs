@@ -0,0 +1,130 @@
+use crate::utils::MapType;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+
+/// Format of the map files, depending on the mta
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MapFormat {
+    // virtual_alias_maps and transport_maps (`key value`)
+    #[default]
+    Postfix,
+    // table(5): mapping table of aliases and list table of odoo addresses
+    Opensmtpd,
+    // lsearch files (`key: value`)
+    Exim,
+    // virtusertable and mailertable (by domain, with a mailer:host nexthop)
+    Sendmail,
+}
+
+/// Odoo addresses and nexthop of a transport entry (`address... nexthop`)
+fn transport(entry: &str) -> (Vec<&str>, String) {
+    let (addresses, nexthop): (Vec<&str>, Vec<&str>) =
+        entry.split(' ').partition(|field| field.contains('@'));
+    (addresses, nexthop.join(" "))
+}
+
+impl MapFormat {
+    /// Render entries in postfix canonical form (`key value`) in this format, one line per
+    /// entry with single spaces
+    pub fn render(&self, map: &MapType, entries: &BTreeSet<String>) -> BTreeSet<String> {
+        match (self, map) {
+            (MapFormat::Postfix, _) => entries.clone(),
+            (MapFormat::Opensmtpd, MapType::Aliases) => entries.clone(),
+            (MapFormat::Sendmail, MapType::Aliases) => entries.clone(),
+            (MapFormat::Exim, MapType::Aliases) => entries
+                .iter()
+                .map(|entry| entry.replacen(' ', ": ", 1))
+                .collect(),
+            (MapFormat::Opensmtpd, MapType::Transport) => entries
+                .iter()
+                .flat_map(|entry| transport(entry).0)
+                .map(str::to_string)
+                .collect(),
+            (MapFormat::Exim, MapType::Transport) => entries
+                .iter()
+                .flat_map(|entry| {
+                    let (addresses, nexthop) = transport(entry);
+                    addresses
+                        .into_iter()
+                        .map(move |address| format!("{}: {}", address, nexthop))
+                })
+                .collect(),
+            (MapFormat::Sendmail, MapType::Transport) => entries
+                .iter()
+                .flat_map(|entry| {
+                    let (addresses, nexthop) = transport(entry);
+                    addresses.into_iter().filter_map(move |address| {
+                        let (_, domain) = address.rsplit_once('@')?;
+                        Some(format!("{} {}", domain, nexthop))
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Shell command compiling the map after it is written, `{path}` being replaced by its path
+    pub fn command(&self, map: &MapType) -> Option<&'static str> {
+        match (self, map) {
+            (MapFormat::Postfix, _) => Some("postmap {path}"),
+            (MapFormat::Opensmtpd, MapType::Aliases) => Some("makemap {path}"),
+            (MapFormat::Opensmtpd, MapType::Transport) => Some("makemap -t set {path}"),
+            // lsearch files are read as is
+            (MapFormat::Exim, _) => None,
+            (MapFormat::Sendmail, _) => Some("makemap hash {path} < {path}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(format: MapFormat, map: MapType, entries: &[&str]) -> Vec<String> {
+        let entries = entries.iter().map(|entry| entry.to_string()).collect();
+        format.render(&map, &entries).into_iter().collect()
+    }
+
+    #[test]
+    fn aliases() {
+        let entries = ["b@x.com a@x.com"];
+        for format in [
+            MapFormat::Postfix,
+            MapFormat::Opensmtpd,
+            MapFormat::Sendmail,
+        ] {
+            assert_eq!(render(format, MapType::Aliases, &entries), entries);
+        }
+        assert_eq!(
+            render(MapFormat::Exim, MapType::Aliases, &entries),
+            ["b@x.com: a@x.com"]
+        );
+    }
+
+    #[test]
+    fn transport() {
+        // odoo transport entries, with several addresses on a line
+        let entries = ["a@x.com lmtp:unix:/s", "b@x.com c@y.com lmtp:unix:/s"];
+        assert_eq!(
+            render(MapFormat::Postfix, MapType::Transport, &entries),
+            entries
+        );
+        assert_eq!(
+            render(MapFormat::Opensmtpd, MapType::Transport, &entries),
+            ["a@x.com", "b@x.com", "c@y.com"]
+        );
+        assert_eq!(
+            render(MapFormat::Exim, MapType::Transport, &entries),
+            [
+                "a@x.com: lmtp:unix:/s",
+                "b@x.com: lmtp:unix:/s",
+                "c@y.com: lmtp:unix:/s"
+            ]
+        );
+        let entries = ["a@x.com esmtp:[odoo]", "b@x.com esmtp:[odoo]"];
+        assert_eq!(
+            render(MapFormat::Sendmail, MapType::Transport, &entries),
+            ["x.com esmtp:[odoo]"]
+        );
+    }
+}
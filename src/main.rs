@@ -1,26 +1,39 @@
 mod args;
 mod cmd;
 mod config;
+mod delivery;
+mod envelope;
 mod errors;
+mod format;
+mod recipients;
+mod refresh;
+mod spool;
+mod status;
+mod tls;
 mod utils;
 
 use crate::{
     args::{Opts, SubCommand},
     cmd::{
         aliases::cmd as aliases, daemon::cmd as daemon, lmtp::cmd as lmtp, pipe::cmd as pipe,
-        transport::cmd as transport, webhook::cmd as webhook,
+        queue::cmd as queue, transport::cmd as transport, webhook::cmd as webhook,
     },
     config::get_config,
+    errors::{ExitError, EX_TEMPFAIL},
 };
-use anyhow::Result;
+use anyhow::{Error, Result};
 
-fn main() {
-    match try_main() {
+#[tokio::main]
+async fn main() {
+    match try_main().await {
         // simple error output: postfix expect the error code at the beginning of reply (4xx)
         Err(err) => {
             eprintln!("{}", err);
             //err.chain().skip(1).for_each(|cause| eprintln!("{}", cause));
-            std::process::exit(1);
+            match err.downcast_ref::<ExitError>() {
+                Some(err) => std::process::exit(err.status),
+                None => std::process::exit(1),
+            }
         }
         Ok(ret) => {
             if let Some(msg) = ret {
@@ -30,18 +43,29 @@ fn main() {
     }
 }
 
-fn try_main() -> Result<Option<String>> {
+async fn try_main() -> Result<Option<String>> {
     let opts: Opts = argh::from_env();
     // get config value in a struct
-    let config = get_config(&opts.config)?;
+    let config = match get_config(&opts.config) {
+        Ok(config) => config,
+        // let postfix defer the message in pipe mode (EX_CONFIG would bounce it)
+        Err(e) if matches!(opts.subcmd, SubCommand::Pipe(_)) => {
+            return Err(Error::new(ExitError::new(
+                EX_TEMPFAIL,
+                &format!("4.3.5 {:#}", e),
+            )))
+        }
+        Err(e) => return Err(e),
+    };
 
     match opts.subcmd {
         // in get mode extract archive to specified directory
-        SubCommand::Pipe(_args) => pipe(&config),
-        SubCommand::Aliases(_) => aliases(&config),
-        SubCommand::Webhook(args) => webhook(config, args, opts.verbose),
-        SubCommand::Lmtp(args) => lmtp(config, args, opts.verbose, opts.debug),
-        SubCommand::Daemon(args) => daemon(config, args, opts.verbose, opts.debug),
-        SubCommand::Transport(_) => transport(&config),
+        SubCommand::Pipe(args) => pipe(&config, args).await,
+        SubCommand::Aliases(args) => aliases(&config, args).await,
+        SubCommand::Webhook(args) => webhook(config, args, opts.verbose).await,
+        SubCommand::Lmtp(args) => lmtp(config, args, opts.verbose, opts.debug).await,
+        SubCommand::Daemon(args) => daemon(config, args, opts.verbose, opts.debug).await,
+        SubCommand::Transport(args) => transport(&config, args).await,
+        SubCommand::Queue(args) => queue(&config, args).await,
     }
 }
@@ -0,0 +1,120 @@
+use crate::{config::Config, utils::MapType};
+use anyhow::Result;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+/// Domain of an address
+fn domain(address: &str) -> Option<&str> {
+    address.rsplit_once('@').map(|(_, domain)| domain)
+}
+
+/// Addresses known by odoo, used to validate recipients at RCPT time
+#[derive(Default)]
+pub struct Recipients {
+    // false until a first alias set has been received: accept everything in that case
+    loaded: bool,
+    // alias -> odoo account
+    aliases: HashMap<String, String>,
+    // odoo accounts (transport map keys)
+    accounts: HashSet<String>,
+    // domains of the aliases and accounts
+    domains: HashSet<String>,
+}
+
+pub type SharedRecipients = Arc<RwLock<Recipients>>;
+
+impl Recipients {
+    /// Build the recipient set from the content of the aliases and transport maps
+    pub fn new(aliases: &str, transport: &str) -> Self {
+        let aliases: HashMap<String, String> = aliases
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next()) {
+                    (Some(alias), Some(account)) => {
+                        Some((alias.to_ascii_lowercase(), account.to_ascii_lowercase()))
+                    }
+                    _ => None,
+                }
+            })
+            .collect();
+        // transport keys are addresses, the nexthop (if any) has no @
+        let accounts: HashSet<String> = transport
+            .split_whitespace()
+            .filter(|field| field.contains('@'))
+            .map(|field| field.to_ascii_lowercase())
+            .collect();
+        let domains = aliases
+            .keys()
+            .chain(accounts.iter())
+            .filter_map(|address| domain(address))
+            .map(str::to_string)
+            .collect();
+        Recipients {
+            loaded: true,
+            aliases,
+            accounts,
+            domains,
+        }
+    }
+
+    /// Get the recipient set from odoo
+    pub async fn fetch(config: &Config) -> Result<Self> {
+        let aliases = MapType::Aliases.get(config).await?;
+        let transport = MapType::Transport.get(config).await?;
+        Ok(Recipients::new(&aliases, &transport))
+    }
+
+    pub fn shared(self) -> SharedRecipients {
+        Arc::new(RwLock::new(self))
+    }
+
+    /// Entries of a map in canonical form, the transport entries having the given nexthop
+    pub fn entries(&self, map: &MapType, nexthop: &str) -> BTreeSet<String> {
+        match map {
+            MapType::Aliases => self
+                .aliases
+                .iter()
+                .map(|(alias, account)| format!("{} {}", alias, account))
+                .collect(),
+            MapType::Transport => self
+                .accounts
+                .iter()
+                .map(|account| format!("{} {}", account, nexthop))
+                .collect(),
+        }
+    }
+
+    /// False until a first alias set has been received
+    pub fn loaded(&self) -> bool {
+        self.loaded
+    }
+
+    /// Odoo account of an alias
+    pub fn account(&self, alias: &str) -> Option<&str> {
+        self.aliases
+            .get(&alias.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// Check that an address is an odoo account (a transport map key)
+    pub fn is_account(&self, address: &str) -> bool {
+        self.accounts.contains(&address.to_ascii_lowercase())
+    }
+
+    /// Check that the domain of an address is managed by odoo
+    pub fn manages(&self, address: &str) -> bool {
+        domain(&address.to_ascii_lowercase()).is_some_and(|domain| self.domains.contains(domain))
+    }
+
+    /// Check that an address is an odoo account or alias
+    pub fn contains(&self, address: &str) -> bool {
+        if !self.loaded {
+            return true;
+        }
+        let address = address.to_ascii_lowercase();
+        self.accounts.contains(&address) || self.aliases.contains_key(&address)
+    }
+}
@@ -0,0 +1,271 @@
+use crate::{
+    cmd::transport::with_nexthop,
+    config::Config,
+    recipients::{Recipients, SharedRecipients},
+    utils::{MapType, MapsLock},
+};
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+// delays in seconds between two attempts to load the maps when they couldn't be loaded at startup
+const RETRY_MIN: u64 = 10;
+const RETRY_MAX: u64 = 600;
+
+/// Last content of a map received from odoo
+#[derive(Default, Clone)]
+struct Cached {
+    data: String,
+    etag: Option<String>,
+}
+
+impl Cached {
+    /// Content after a request to odoo, the cached one if it answered it didn't change
+    fn update(&self, fetched: Option<(String, Option<String>)>) -> Cached {
+        match fetched {
+            Some((data, etag)) => Cached { data, etag },
+            None => self.clone(),
+        }
+    }
+}
+
+/// Get a map from odoo, returning the cached content if it didn't change
+async fn fetch(config: &Config, map: MapType, cached: &Cached) -> Result<Cached> {
+    let fetched = map.fetch(config, cached.etag.as_deref()).await?;
+    Ok(cached.update(fetched))
+}
+
+/// Replace the maps and the shared recipients with the aliases and the odoo addresses received
+/// from odoo, unless they are refused
+async fn replace(
+    config: &Config,
+    recipients: &SharedRecipients,
+    aliases: &str,
+    transport: &str,
+) -> Result<()> {
+    let transport = with_nexthop(config, transport);
+    let lock = MapsLock::acquire(config).await?;
+    // check both maps before replacing any of them
+    MapType::Aliases
+        .check(config, aliases, false, recipients)
+        .await?;
+    MapType::Transport
+        .check(config, &transport, false, recipients)
+        .await?;
+    MapType::Aliases
+        .write(config, aliases, false, recipients, &lock)
+        .await?;
+    MapType::Transport
+        .write(config, &transport, false, recipients, &lock)
+        .await?;
+    *recipients.write().unwrap() = Recipients::new(aliases, &transport);
+    Ok(())
+}
+
+/// Apply the content received from odoo if it changed since the last update, returning whether
+/// the maps were replaced
+async fn update(
+    config: &Config,
+    recipients: &SharedRecipients,
+    aliases: &mut Cached,
+    transport: &mut Cached,
+    new_aliases: Cached,
+    new_transport: Cached,
+) -> Result<bool> {
+    let loaded = recipients.read().unwrap().loaded();
+    if loaded && new_aliases.data == aliases.data && new_transport.data == transport.data {
+        return Ok(false);
+    }
+    replace(config, recipients, &new_aliases.data, &new_transport.data).await?;
+    // only remember the content once the maps are written, to retry refused updates
+    *aliases = new_aliases;
+    *transport = new_transport;
+    Ok(true)
+}
+
+/// Refresh the maps and the shared recipients if the content received from odoo changed
+async fn refresh(
+    config: &Config,
+    recipients: &SharedRecipients,
+    aliases: &mut Cached,
+    transport: &mut Cached,
+) -> Result<()> {
+    let new_aliases = fetch(config, MapType::Aliases, aliases).await?;
+    let new_transport = fetch(config, MapType::Transport, transport).await?;
+    update(
+        config,
+        recipients,
+        aliases,
+        transport,
+        new_aliases,
+        new_transport,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Series of failed refreshes, alerted once when it lasts
+#[derive(Default)]
+struct Failing {
+    // first failure of the current series, and if it was already alerted
+    since: Option<(Instant, bool)>,
+}
+
+impl Failing {
+    /// Record a failure, returning for how long the refresh has been failing if it must be alerted
+    fn failed(&mut self, now: Instant, alert: u64) -> Option<u64> {
+        let (since, alerted) = self.since.get_or_insert((now, false));
+        let elapsed = now.duration_since(*since).as_secs();
+        if *alerted || elapsed < alert {
+            return None;
+        }
+        *alerted = true;
+        Some(elapsed)
+    }
+
+    /// Record a success, returning for how long the refresh had been failing
+    fn succeeded(&mut self, now: Instant) -> Option<u64> {
+        let (since, _) = self.since.take()?;
+        Some(now.duration_since(since).as_secs())
+    }
+}
+
+/// Load the maps from odoo until it succeeds if they couldn't be loaded at startup, then refresh
+/// them periodically if configured, in case a webhook call was lost. Requests are conditional
+/// (If-None-Match) when odoo gives an etag.
+pub async fn run(config: &Config, recipients: SharedRecipients) {
+    let mut aliases = Cached::default();
+    let mut transport = Cached::default();
+    // lookups are deferred and recipients not validated until then
+    let mut delay = RETRY_MIN;
+    while !recipients.read().unwrap().loaded() {
+        tokio::time::sleep(Duration::from_secs(delay)).await;
+        match refresh(config, &recipients, &mut aliases, &mut transport).await {
+            Ok(()) => println!("maps loaded from odoo"),
+            Err(e) => eprintln!("can't load maps: {}", e),
+        }
+        delay = (delay * 2).min(RETRY_MAX);
+    }
+    if config.refresh == 0 {
+        return std::future::pending().await;
+    }
+    let mut failing = Failing::default();
+    loop {
+        tokio::time::sleep(Duration::from_secs(config.refresh)).await;
+        match refresh(config, &recipients, &mut aliases, &mut transport).await {
+            Ok(()) => {
+                if let Some(elapsed) = failing.succeeded(Instant::now()) {
+                    println!("maps refreshed after failing for {}s", elapsed);
+                }
+            }
+            Err(e) => {
+                eprintln!("can't refresh maps: {}", e);
+                if let Some(elapsed) = failing.failed(Instant::now(), config.refresh_alert) {
+                    eprintln!("ALERT maps not refreshed from odoo for {}s", elapsed);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+
+    fn cached(data: &str, etag: Option<&str>) -> Cached {
+        Cached {
+            data: data.to_string(),
+            etag: etag.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn not_modified() {
+        let cached = cached("b@x.com a@x.com", Some("\"1\""));
+        // 304 answer to If-None-Match
+        let same = cached.update(None);
+        assert_eq!(same.data, cached.data);
+        assert_eq!(same.etag, cached.etag);
+        let new = cached.update(Some(("c@x.com a@x.com".to_string(), None)));
+        assert_eq!(new.data, "c@x.com a@x.com");
+        assert_eq!(new.etag, None);
+    }
+
+    #[tokio::test]
+    async fn update_maps() {
+        let config = test_config("refresh");
+        let recipients = Recipients::default().shared();
+        let (mut aliases, mut transport) = (Cached::default(), Cached::default());
+        assert!(update(
+            &config,
+            &recipients,
+            &mut aliases,
+            &mut transport,
+            cached("b@x.com a@x.com", Some("\"1\"")),
+            cached("a@x.com", Some("\"2\"")),
+        )
+        .await
+        .unwrap());
+        assert_eq!(aliases.etag.as_deref(), Some("\"1\""));
+        assert_eq!(
+            recipients.read().unwrap().account("b@x.com"),
+            Some("a@x.com")
+        );
+        // unchanged content is skipped, even with a new etag
+        std::fs::remove_file(&config.aliases).unwrap();
+        assert!(!update(
+            &config,
+            &recipients,
+            &mut aliases,
+            &mut transport,
+            cached("b@x.com a@x.com", Some("\"3\"")),
+            cached("a@x.com", None),
+        )
+        .await
+        .unwrap());
+        assert!(!std::path::Path::new(&config.aliases).exists());
+        // but applied while the recipients are not loaded
+        *recipients.write().unwrap() = Recipients::default();
+        assert!(update(
+            &config,
+            &recipients,
+            &mut aliases,
+            &mut transport,
+            cached("b@x.com a@x.com", Some("\"3\"")),
+            cached("a@x.com", None),
+        )
+        .await
+        .unwrap());
+        assert!(recipients.read().unwrap().loaded());
+        // a refused update is retried with the next content received
+        let refused = update(
+            &config,
+            &recipients,
+            &mut aliases,
+            &mut transport,
+            cached("b@x.com a@x.com\nc d", None),
+            cached("a@x.com", None),
+        )
+        .await;
+        assert!(refused.is_err());
+        assert_eq!(aliases.data, "b@x.com a@x.com");
+        assert_eq!(aliases.etag.as_deref(), Some("\"3\""));
+        assert_eq!(recipients.read().unwrap().account("c"), None);
+    }
+
+    #[test]
+    fn alert() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut failing = Failing::default();
+        assert_eq!(failing.succeeded(at(0)), None);
+        assert_eq!(failing.failed(at(0), 3600), None);
+        assert_eq!(failing.failed(at(1800), 3600), None);
+        assert_eq!(failing.failed(at(3600), 3600), Some(3600));
+        // alerted once per series
+        assert_eq!(failing.failed(at(5400), 3600), None);
+        assert_eq!(failing.succeeded(at(7200)), Some(7200));
+        assert_eq!(failing.failed(at(9000), 3600), None);
+        assert_eq!(failing.failed(at(12600), 3600), Some(3600));
+    }
+}
@@ -0,0 +1,314 @@
+use crate::{
+    config::Config,
+    delivery::{deliver, Delivery},
+    status::StatusMap,
+    utils::write_atomic,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, TryLockError},
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, SystemTime},
+};
+use tokio::{fs, task::spawn_blocking};
+
+// delay before the first retry, doubled after each attempt
+const RETRY_DELAY: u64 = 60;
+// maximum delay between two attempts
+const MAX_RETRY_DELAY: u64 = 3600;
+
+/// Current unix time in seconds
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Metadata of a spooled message, stored in `<id>.yml` next to its content in `<id>.eml`
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub sender: String,
+    pub recipient: String,
+    // unix time when the message was spooled
+    pub created: u64,
+    // number of delivery attempts from the spool
+    pub attempts: u32,
+    // unix time of the next delivery attempt
+    pub next_attempt: u64,
+    // last error returned by odoo
+    #[serde(default)]
+    pub last_error: String,
+    // not retried anymore: odoo refused the message
+    #[serde(default)]
+    pub held: bool,
+}
+
+/// Exclusive right to change a spooled message, between the delivery worker and the queue
+/// commands, released when dropped
+pub struct MessageLock {
+    _file: File,
+}
+
+/// Directory of messages accepted while odoo was unavailable
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: &str) -> Result<Self> {
+        std::fs::create_dir_all(dir).with_context(|| format!("Can't create spool {}", dir))?;
+        Ok(Spool {
+            dir: PathBuf::from(dir),
+        })
+    }
+
+    fn path(&self, id: &str, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", id, ext))
+    }
+
+    /// Lock a message, waiting for it unless wait is false (None if it is locked). The content
+    /// is locked since the metadata is replaced on each save. Fail if the message is gone.
+    pub async fn lock(&self, id: &str, wait: bool) -> Result<Option<MessageLock>> {
+        let path = self.path(id, "eml");
+        let id = id.to_string();
+        spawn_blocking(move || {
+            let file =
+                File::open(&path).with_context(|| format!("No message {} in the spool", id))?;
+            if wait {
+                file.lock()?;
+            } else {
+                match file.try_lock() {
+                    Ok(()) => (),
+                    Err(TryLockError::WouldBlock) => return Ok(None),
+                    Err(TryLockError::Error(e)) => return Err(e.into()),
+                }
+            }
+            Ok(Some(MessageLock { _file: file }))
+        })
+        .await?
+    }
+
+    /// Save the metadata of a message
+    pub async fn save(&self, id: &str, entry: &Entry) -> Result<()> {
+        write_atomic(
+            &self.path(id, "yml"),
+            serde_yaml::to_string(entry)?.as_bytes(),
+        )
+        .await
+    }
+
+    /// Spool a message (with its envelope headers) for a recipient after a failed delivery,
+    /// returning its id once it is safely on disk
+    pub async fn add(
+        &self,
+        sender: &str,
+        recipient: &str,
+        content: &[u8],
+        error: &str,
+    ) -> Result<String> {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let id = format!(
+            "{:X}{:05X}{:04X}",
+            time.as_secs(),
+            time.subsec_micros(),
+            COUNTER.fetch_add(1, Ordering::Relaxed) & 0xffff
+        );
+        // the content first: the metadata makes the message visible to the worker
+        write_atomic(&self.path(&id, "eml"), content).await?;
+        let created = now();
+        let entry = Entry {
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+            created,
+            attempts: 0,
+            next_attempt: created + RETRY_DELAY,
+            last_error: error.to_string(),
+            held: false,
+        };
+        self.save(&id, &entry).await?;
+        Ok(id)
+    }
+
+    /// Spooled messages, oldest first
+    pub async fn entries(&self) -> Result<Vec<(String, Entry)>> {
+        let mut entries = Vec::new();
+        let mut dir = fs::read_dir(&self.dir).await?;
+        while let Some(file) = dir.next_entry().await? {
+            let path = file.path();
+            if path.extension().is_some_and(|ext| ext == "yml") {
+                let id = path.file_stem().unwrap().to_string_lossy().to_string();
+                match self.load(&id).await {
+                    Ok(entry) => entries.push((id, entry)),
+                    Err(e) => eprintln!("can't read spooled message {}: {}", id, e),
+                }
+            }
+        }
+        entries
+            .sort_by(|(a, entry_a), (b, entry_b)| (entry_a.created, a).cmp(&(entry_b.created, b)));
+        Ok(entries)
+    }
+
+    /// Metadata of a message
+    pub async fn load(&self, id: &str) -> Result<Entry> {
+        let data = fs::read(self.path(id, "yml"))
+            .await
+            .with_context(|| format!("No message {} in the spool", id))?;
+        Ok(serde_yaml::from_slice(&data)?)
+    }
+
+    /// Content of a message
+    pub async fn content(&self, id: &str) -> Result<Vec<u8>> {
+        Ok(fs::read(self.path(id, "eml")).await?)
+    }
+
+    /// Size of the content of a message
+    pub async fn size(&self, id: &str) -> Result<u64> {
+        Ok(fs::metadata(self.path(id, "eml")).await?.len())
+    }
+
+    /// Remove a message
+    pub async fn remove(&self, id: &str) -> Result<()> {
+        // the metadata first: a message without content is never visible
+        fs::remove_file(self.path(id, "yml")).await?;
+        fs::remove_file(self.path(id, "eml")).await?;
+        Ok(())
+    }
+
+    /// Try to deliver a message now if it is due, returning its metadata unless it was delivered
+    /// or it is gone. A message locked by a queue command is left for the next run.
+    async fn retry(
+        &self,
+        config: &Config,
+        status_map: &StatusMap,
+        id: &str,
+    ) -> Result<Option<Entry>> {
+        let _lock = match self.lock(id, false).await? {
+            Some(lock) => lock,
+            None => return Ok(None),
+        };
+        // the metadata may have been changed before the lock was taken
+        let mut entry = self.load(id).await?;
+        if entry.held || entry.next_attempt > now() {
+            return Ok(Some(entry));
+        }
+        let delivery = deliver(config, self.content(id).await?).await;
+        entry.attempts += 1;
+        if let Delivery::Delivered(_) = delivery {
+            println!("spooled message {} delivered to {}", id, entry.recipient);
+            self.remove(id).await?;
+            return Ok(None);
+        }
+        eprintln!("spooled message {} not delivered: {}", id, delivery);
+        // temporary failure
+        if status_map.map(&delivery).0 < 500 {
+            let delay = RETRY_DELAY
+                .saturating_mul(1 << entry.attempts.min(16))
+                .min(MAX_RETRY_DELAY);
+            entry.next_attempt = now() + delay;
+        } else {
+            // already accepted with 250 and no bounce is sent
+            eprintln!(
+                "ALERT spooled message {} from <{}> to <{}> undeliverable, held (the sender is not \
+                 notified): {}",
+                id, entry.sender, entry.recipient, delivery
+            );
+            entry.held = true;
+        }
+        entry.last_error = delivery.to_string();
+        self.save(id, &entry).await?;
+        Ok(Some(entry))
+    }
+
+    /// Deliver the spooled messages in the background, retrying with exponential backoff
+    pub async fn run(&self, config: &Config, status_map: &StatusMap) {
+        loop {
+            // look at the spool at least every retry delay for messages added by other processes
+            let mut next = now() + RETRY_DELAY;
+            match self.entries().await {
+                Ok(entries) => {
+                    for (id, mut entry) in entries {
+                        if !entry.held && entry.next_attempt <= now() {
+                            entry = match self.retry(config, status_map, &id).await {
+                                Ok(Some(entry)) => entry,
+                                Ok(None) => continue,
+                                Err(e) => {
+                                    eprintln!("can't retry spooled message {}: {}", id, e);
+                                    continue;
+                                }
+                            }
+                        }
+                        if !entry.held {
+                            next = next.min(entry.next_attempt);
+                        }
+                    }
+                }
+                Err(e) => eprintln!("can't read spool: {}", e),
+            }
+            tokio::time::sleep(Duration::from_secs(next.saturating_sub(now()))).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::test_config;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn retry_with_queue_commands() {
+        let mut config = test_config("spool");
+        config.host = "127.0.0.1:1".to_string();
+        let status_map = StatusMap::new(&[]).unwrap();
+        let dir = Path::new(&config.aliases).parent().unwrap().join("spool");
+        let spool = Spool::new(&dir.to_string_lossy()).unwrap();
+        let id = spool
+            .add("s@y.com", "a@x.com", b"Subject: x\n\n", "error")
+            .await
+            .unwrap();
+        let mut entry = spool.load(&id).await.unwrap();
+        entry.next_attempt = 0;
+        spool.save(&id, &entry).await.unwrap();
+        // locked by a queue command: left for the next run
+        let lock = spool.lock(&id, true).await.unwrap();
+        assert!(spool.lock(&id, false).await.unwrap().is_none());
+        assert!(spool
+            .retry(&config, &status_map, &id)
+            .await
+            .unwrap()
+            .is_none());
+        // held before the worker got the lock
+        entry.held = true;
+        spool.save(&id, &entry).await.unwrap();
+        drop(lock);
+        let entry = spool
+            .retry(&config, &status_map, &id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(entry.held);
+        assert_eq!(entry.attempts, 0);
+        // temporary failure
+        let mut entry = spool.load(&id).await.unwrap();
+        entry.held = false;
+        spool.save(&id, &entry).await.unwrap();
+        let entry = spool
+            .retry(&config, &status_map, &id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.attempts, 1);
+        assert!(entry.next_attempt > now());
+        assert_eq!(spool.load(&id).await.unwrap().attempts, 1);
+        // deleted
+        spool.remove(&id).await.unwrap();
+        assert!(spool.lock(&id, true).await.is_err());
+        assert!(spool.retry(&config, &status_map, &id).await.is_err());
+    }
+}
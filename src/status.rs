@@ -0,0 +1,211 @@
+use crate::delivery::Delivery;
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+/// Http status of a rule: a code (403), a class ("4xx") or "error" when odoo can't be reached
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum HttpStatus {
+    Code(u16),
+    Name(String),
+}
+
+#[derive(Deserialize, Clone)]
+pub struct StatusRule {
+    pub http: HttpStatus,
+    // regex matched against the first line of the odoo reply (or the connection error)
+    pub body: Option<String>,
+    // smtp reply code
+    pub code: u16,
+    // enhanced status code (RFC 3463)
+    pub status: String,
+}
+
+impl StatusRule {
+    fn new(http: HttpStatus, code: u16, status: &str) -> Self {
+        StatusRule {
+            http,
+            body: None,
+            code,
+            status: status.to_string(),
+        }
+    }
+}
+
+/// Rules used when no configured rule matches: permanent failure for refused messages, temporary
+/// failure when odoo is unavailable or misconfigured
+fn default_rules() -> Vec<StatusRule> {
+    use HttpStatus::*;
+    vec![
+        StatusRule::new(Name("error".to_string()), 421, "4.4.1"),
+        StatusRule::new(Code(401), 421, "4.7.0"),
+        StatusRule::new(Code(408), 451, "4.4.2"),
+        StatusRule::new(Code(429), 451, "4.7.0"),
+        StatusRule::new(Code(502), 451, "4.4.1"),
+        StatusRule::new(Code(503), 451, "4.4.1"),
+        StatusRule::new(Code(504), 451, "4.4.2"),
+        StatusRule::new(Name("5xx".to_string()), 451, "4.3.0"),
+        StatusRule::new(Code(403), 550, "5.7.1"),
+        StatusRule::new(Code(404), 550, "5.1.1"),
+        StatusRule::new(Name("4xx".to_string()), 554, "5.0.0"),
+    ]
+}
+
+/// Compiled rule
+struct Rule {
+    http: HttpStatus,
+    body: Option<Regex>,
+    code: u16,
+    status: String,
+}
+
+impl Rule {
+    fn matches(&self, delivery: &Delivery) -> bool {
+        let (code, text) = match delivery {
+            Delivery::Delivered(_) => return false,
+            Delivery::Rejected(code, msg) => (Some(*code), msg.clone()),
+            Delivery::Failed(e) => (None, e.to_string()),
+        };
+        let http = match (&self.http, code) {
+            (HttpStatus::Code(rule), Some(code)) => *rule == code,
+            (HttpStatus::Name(name), None) => name == "error",
+            (HttpStatus::Name(name), Some(code)) => {
+                name.len() == 3
+                    && name[1..].eq_ignore_ascii_case("xx")
+                    && name[..1] == (code / 100).to_string()
+            }
+            _ => false,
+        };
+        http && self.body.as_ref().is_none_or(|body| body.is_match(&text))
+    }
+}
+
+/// Mapping from odoo replies to smtp reply and enhanced status codes
+pub struct StatusMap {
+    rules: Vec<Rule>,
+}
+
+impl StatusMap {
+    /// Compile the configured rules, tried in order before the default ones
+    pub fn new(rules: &[StatusRule]) -> Result<Self> {
+        let status = Regex::new(r"^[245]\.[0-9]{1,3}\.[0-9]{1,3}$").unwrap();
+        let rules = rules
+            .iter()
+            .cloned()
+            .chain(default_rules())
+            .map(|rule| {
+                if !(400..600).contains(&rule.code) {
+                    return Err(anyhow!("Invalid smtp code {} in status_map", rule.code));
+                }
+                // same class as the smtp code
+                if !status.is_match(&rule.status)
+                    || rule.status[..1] != (rule.code / 100).to_string()
+                {
+                    return Err(anyhow!(
+                        "Invalid enhanced status {} for smtp code {} in status_map",
+                        rule.status,
+                        rule.code
+                    ));
+                }
+                if let HttpStatus::Name(name) = &rule.http {
+                    let class = name.is_ascii()
+                        && name.len() == 3
+                        && ["4", "5"].contains(&&name[..1])
+                        && name[1..].eq_ignore_ascii_case("xx");
+                    if !class && name != "error" {
+                        return Err(anyhow!("Invalid http status {} in status_map", name));
+                    }
+                }
+                Ok(Rule {
+                    http: rule.http,
+                    body: rule.body.as_deref().map(Regex::new).transpose()?,
+                    code: rule.code,
+                    status: rule.status,
+                })
+            })
+            .collect::<Result<Vec<Rule>>>()?;
+        Ok(StatusMap { rules })
+    }
+
+    /// Smtp reply and enhanced status codes of a delivery
+    pub fn map(&self, delivery: &Delivery) -> (u16, &str) {
+        if let Delivery::Delivered(_) = delivery {
+            return (250, "2.0.0");
+        }
+        match self.rules.iter().find(|rule| rule.matches(delivery)) {
+            Some(rule) => (rule.code, &rule.status),
+            // other http statuses (3xx) are unexpected
+            None => (451, "4.3.0"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(yaml: &str) -> StatusMap {
+        StatusMap::new(&serde_yaml::from_str::<Vec<StatusRule>>(yaml).unwrap()).unwrap()
+    }
+
+    fn rejected(code: u16, msg: &str) -> Delivery {
+        Delivery::Rejected(code, msg.to_string())
+    }
+
+    #[test]
+    fn default_rules() {
+        let map = rules("[]");
+        assert_eq!(map.map(&Delivery::Delivered(String::new())), (250, "2.0.0"));
+        assert_eq!(map.map(&rejected(401, "")), (421, "4.7.0"));
+        assert_eq!(map.map(&rejected(404, "")), (550, "5.1.1"));
+        assert_eq!(map.map(&rejected(410, "")), (554, "5.0.0"));
+        assert_eq!(map.map(&rejected(503, "")), (451, "4.4.1"));
+        assert_eq!(map.map(&rejected(500, "")), (451, "4.3.0"));
+        assert_eq!(map.map(&rejected(302, "")), (451, "4.3.0"));
+    }
+
+    #[test]
+    fn configured_rules_first() {
+        let map = rules(
+            "[{http: 403, body: quota, code: 452, status: 4.2.2}, \
+             {http: 4xx, code: 451, status: 4.3.0}, \
+             {http: 403, code: 553, status: 5.7.0}]",
+        );
+        assert_eq!(map.map(&rejected(403, "over quota")), (452, "4.2.2"));
+        // the first matching rule wins, even if a later one is more specific
+        assert_eq!(map.map(&rejected(403, "denied")), (451, "4.3.0"));
+        assert_eq!(map.map(&rejected(500, "")), (451, "4.3.0"));
+    }
+
+    #[tokio::test]
+    async fn connection_error() {
+        let error = reqwest::Client::new()
+            .get("http://")
+            .send()
+            .await
+            .unwrap_err();
+        let map = rules("[{http: 5xx, code: 554, status: 5.0.0}]");
+        assert_eq!(map.map(&Delivery::Failed(error)), (421, "4.4.1"));
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for yaml in [
+            "[{http: 403, code: 250, status: 2.0.0}]",
+            "[{http: 6xx, code: 550, status: 5.0.0}]",
+            "[{http: 403, body: '(', code: 550, status: 5.0.0}]",
+            "[{http: é4, code: 550, status: 5.0.0}]",
+            "[{http: 4é, code: 550, status: 5.0.0}]",
+            "[{http: 403, code: 550, status: 4.0.0}]",
+            "[{http: 403, code: 450, status: 5.0.0}]",
+            "[{http: 403, code: 550, status: 5.0}]",
+            "[{http: 403, code: 550, status: 5.0.1000}]",
+            "[{http: 403, code: 550, status: é.0.0}]",
+            "[{http: 403, code: 550, status: ''}]",
+        ] {
+            let rules = serde_yaml::from_str::<Vec<StatusRule>>(yaml).unwrap();
+            assert!(StatusMap::new(&rules).is_err());
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use crate::config::Tls;
+use anyhow::{Context, Result};
+use ring::digest::{digest, SHA256};
+use rustls::{
+    client::danger::HandshakeSignatureValid,
+    crypto::{
+        ring::default_provider, verify_tls12_signature, verify_tls13_signature,
+        WebPkiSupportedAlgorithms,
+    },
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, UnixTime},
+    server::{
+        danger::{ClientCertVerified, ClientCertVerifier},
+        WebPkiClientVerifier,
+    },
+    DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig, SignatureScheme,
+};
+use std::sync::Arc;
+
+/// Hex encoded sha256 fingerprint of a certificate
+fn fingerprint(cert: &[u8]) -> String {
+    digest(&SHA256, cert)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Accept only client certificates whose fingerprint is in the allowlist, after verifying
+/// their chain if a client ca is configured
+#[derive(Debug)]
+struct AllowedClients {
+    verifier: Option<Arc<dyn ClientCertVerifier>>,
+    fingerprints: Vec<String>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ClientCertVerifier for AllowedClients {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        match &self.verifier {
+            Some(verifier) => verifier.root_hint_subjects(),
+            None => &[],
+        }
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        if let Some(verifier) = &self.verifier {
+            verifier.verify_client_cert(end_entity, intermediates, now)?;
+        }
+        if self.fingerprints.contains(&fingerprint(end_entity)) {
+            Ok(ClientCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "client certificate not allowed".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Build a tls server configuration from the listener tls settings
+pub fn server_config(tls: &Tls) -> Result<Arc<ServerConfig>> {
+    let provider = Arc::new(default_provider());
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Can't read certificates from {}", &tls.cert))?;
+    let key = PrivateKeyDer::from_pem_file(&tls.key)
+        .with_context(|| format!("Can't read private key from {}", &tls.key))?;
+
+    // verify client certificates chain
+    let verifier = match &tls.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(client_ca)
+                .with_context(|| format!("Can't read certificates from {}", client_ca))?
+            {
+                roots.add(cert?)?;
+            }
+            Some(
+                WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                    .build()?,
+            )
+        }
+        None => None,
+    };
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = if !tls.allowed_clients.is_empty() {
+        // fingerprints can be written in upper case with colons (openssl x509 -fingerprint)
+        let fingerprints = tls
+            .allowed_clients
+            .iter()
+            .map(|fingerprint| fingerprint.replace(':', "").to_ascii_lowercase())
+            .collect();
+        builder.with_client_cert_verifier(Arc::new(AllowedClients {
+            verifier,
+            fingerprints,
+            algorithms: provider.signature_verification_algorithms,
+        }))
+    } else if let Some(verifier) = verifier {
+        builder.with_client_cert_verifier(verifier)
+    } else {
+        builder.with_no_client_auth()
+    };
+    Ok(Arc::new(builder.with_single_cert(certs, key)?))
+}
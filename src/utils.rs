@@ -1,14 +1,29 @@
-use crate::{config::Config, errors::HttpError};
-use anyhow::{Context, Error, Result};
+use crate::{
+    config::Config,
+    errors::{HttpError, MapError},
+    recipients::SharedRecipients,
+};
+use anyhow::{anyhow, Context, Error, Result};
+use reqwest::Client;
 use std::{
-    env,
-    fs::File,
-    io::Write,
-    os::unix::io::FromRawFd,
+    collections::BTreeSet,
+    env, fmt,
+    fs::{remove_file, set_permissions, File, Permissions},
+    io::{self, Write},
+    os::unix::{fs::PermissionsExt, io::FromRawFd},
     path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UnixListener},
     process::Command,
+    signal::unix::{signal, SignalKind},
+    sync::{Mutex, MutexGuard},
+    task::spawn_blocking,
 };
-use ureq::get;
 
 pub fn which<P>(name: P) -> Option<PathBuf>
 where
@@ -31,7 +46,163 @@ where
 pub fn s6_ready(fd: Option<i32>) {
     if let Some(fd) = fd {
         let mut f = unsafe { File::from_raw_fd(fd) };
-        let _ = write!(&mut f, "\n");
+        let _ = writeln!(&mut f);
+    }
+}
+
+/// Http client shared by all the requests to odoo
+pub fn client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .build()
+            .expect("Can't initialize the http client")
+    })
+}
+
+/// Wait for SIGINT or SIGTERM
+pub async fn shutdown() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Can't listen to SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Can't listen to SIGINT");
+    tokio::select! {
+        _ = sigterm.recv() => (),
+        _ = sigint.recv() => (),
+    }
+}
+
+/// Connection accepted by a listener
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Listener on a unix socket or a tcp address
+pub enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Listener {
+    /// Listen on a unix socket if the address is a path (starting with /), on tcp otherwise
+    pub async fn bind(address: &str) -> Result<Self> {
+        if address.starts_with('/') {
+            let _ = remove_file(address);
+            let listener = UnixListener::bind(address)
+                .with_context(|| format!("Can't listen on {}", address))?;
+            set_permissions(address, Permissions::from_mode(0o666))?;
+            Ok(Listener::Unix(listener))
+        } else {
+            let listener = TcpListener::bind(address)
+                .await
+                .with_context(|| format!("Can't listen on {}", address))?;
+            Ok(Listener::Tcp(listener))
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<Box<dyn Stream>> {
+        Ok(match self {
+            Listener::Unix(listener) => Box::new(listener.accept().await?.0),
+            Listener::Tcp(listener) => Box::new(listener.accept().await?.0),
+        })
+    }
+}
+
+/// Replace a file atomically: write a temporary file in the same directory, sync it to disk and
+/// rename it over the original one, keeping its permissions. The temporary file is named after
+/// the process so that another process can't write it at the same time.
+pub async fn write_atomic(path: &Path, buf: &[u8]) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().context("Invalid file name")?;
+    let tmp = dir.join(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    let mut file = fs::File::create(&tmp)
+        .await
+        .with_context(|| format!("Can't open {}", tmp.display()))?;
+    if let Ok(metadata) = fs::metadata(path).await {
+        file.set_permissions(metadata.permissions()).await?;
+    }
+    file.write_all(buf).await?;
+    file.sync_all().await?;
+    fs::rename(&tmp, path).await?;
+    // persist the rename
+    fs::File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+/// Exclusive right to update the maps, in this process and between processes
+pub struct MapsLock {
+    _guard: MutexGuard<'static, ()>,
+    _file: Option<File>,
+}
+
+impl MapsLock {
+    pub async fn acquire(config: &Config) -> Result<Self> {
+        static LOCK: Mutex<()> = Mutex::const_new(());
+        let guard = LOCK.lock().await;
+        // lock file next to the aliases map, released when closed
+        if config.aliases.is_empty() {
+            return Ok(MapsLock {
+                _guard: guard,
+                _file: None,
+            });
+        }
+        let path = format!("{}.lock", &config.aliases);
+        let file = spawn_blocking(move || -> Result<File> {
+            let file = File::create(&path).with_context(|| format!("Can't open {}", path))?;
+            file.lock()?;
+            Ok(file)
+        })
+        .await??;
+        Ok(MapsLock {
+            _guard: guard,
+            _file: Some(file),
+        })
+    }
+}
+
+/// Canonical form of a map: one entry per line with single spaces, sorted and deduplicated
+fn canonical(data: &str) -> BTreeSet<String> {
+    data.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Entries added and removed between two versions of a map
+#[derive(Debug)]
+pub struct MapDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl MapDiff {
+    fn new(old: &BTreeSet<String>, new: &BTreeSet<String>) -> Self {
+        MapDiff {
+            added: new.difference(old).cloned().collect(),
+            removed: old.difference(new).cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl fmt::Display for MapDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in &self.removed {
+            writeln!(f, "-{}", entry)?;
+        }
+        for entry in &self.added {
+            writeln!(f, "+{}", entry)?;
+        }
+        Ok(())
     }
 }
 
@@ -41,35 +212,361 @@ pub enum MapType {
 }
 
 impl MapType {
-    pub fn write(&self, config: &Config, buf: &[u8]) -> Result<()> {
-        let map = match self {
+    fn name(&self) -> &'static str {
+        match self {
+            MapType::Aliases => "aliases",
+            MapType::Transport => "transport",
+        }
+    }
+
+    fn path<'a>(&self, config: &'a Config) -> &'a str {
+        match self {
             MapType::Aliases => &config.aliases,
             MapType::Transport => &config.transport,
+        }
+    }
+
+    /// Current entries of the map, the ones of the shared recipients when it is not written
+    async fn entries(
+        &self,
+        config: &Config,
+        recipients: &SharedRecipients,
+    ) -> Result<BTreeSet<String>> {
+        let map = self.path(config);
+        if map.is_empty() {
+            let entries = recipients.read().unwrap().entries(self, &config.nexthop());
+            return Ok(config.map_format.render(self, &entries));
+        }
+        match fs::read_to_string(map).await {
+            Ok(current) => Ok(canonical(&current)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+            Err(e) => Err(Error::new(e).context(format!("Can't read {}", map))),
+        }
+    }
+
+    /// Check that new content can replace the map: it must be valid and not remove more than
+    /// max_shrink percent of the entries, unless forced. Return the changes.
+    pub async fn check(
+        &self,
+        config: &Config,
+        data: &str,
+        force: bool,
+        recipients: &SharedRecipients,
+    ) -> Result<MapDiff> {
+        let refuse = |reason: String| Err(Error::new(MapError::new(&reason)));
+        let entries = canonical(data);
+        let fields = match self {
+            MapType::Aliases => 2..=2,
+            MapType::Transport => 2..=usize::MAX,
         };
-        let mut file = File::create(map).with_context(|| format!("Can't open {}", map))?;
-        // write the map file
-        file.write_all(buf)?;
-        // execute postmap
-        if let Some(postmap) = which("postmap") {
-            Command::new(postmap).args(&[map]).status()?;
+        if let Some(entry) = entries
+            .iter()
+            .find(|entry| !fields.contains(&entry.split(' ').count()) || !entry.contains('@'))
+        {
+            return refuse(format!("invalid {} entry: {}", self.name(), entry));
         }
-        Ok(())
+        let entries = config.map_format.render(self, &entries);
+        let current = self.entries(config, recipients).await?;
+        if !force && entries.is_empty() && !current.is_empty() {
+            return refuse(format!("empty {} map", self.name()));
+        }
+        if !force && current.len() * (100 - config.max_shrink.min(100)) > entries.len() * 100 {
+            return refuse(format!(
+                "{} map would shrink from {} to {} entries",
+                self.name(),
+                current.len(),
+                entries.len()
+            ));
+        }
+        Ok(MapDiff::new(&current, &entries))
     }
 
-    pub fn get(&self, config: &Config) -> Result<String> {
-        let path = match self {
-            MapType::Aliases => "aliases",
-            MapType::Transport => "transport",
+    /// Commands run after writing the map: the configured hooks, or the compile command of the
+    /// map format if the mta tools are installed
+    fn hooks(&self, config: &Config) -> Vec<String> {
+        let hooks = match self {
+            MapType::Aliases => &config.hooks.aliases,
+            MapType::Transport => &config.hooks.transport,
         };
-        let url = format!("https://{}/mail_delivery/{}", &config.host, path);
-        let resp = get(&url).set("X-Mail-Token", &config.token).call();
-        if resp.ok() {
-            let text = resp.into_string()?;
-            Ok(text)
+        match hooks {
+            Some(hooks) => hooks.clone(),
+            None => config
+                .map_format
+                .command(self)
+                .filter(|command| command.split(' ').next().and_then(which).is_some())
+                .map(|command| vec![command.to_string()])
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Run the hooks of the map, failing on the first command that fails
+    async fn run_hooks(&self, config: &Config) -> Result<()> {
+        for hook in self.hooks(config) {
+            let command = hook.replace("{path}", self.path(config));
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(&command)
+                .output()
+                .await
+                .with_context(|| format!("Can't run {}", command))?;
+            let text = String::from_utf8_lossy(&[output.stdout, output.stderr].concat())
+                .trim()
+                .to_string();
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "`{}` failed ({}): {}",
+                    command,
+                    output.status,
+                    text
+                ));
+            }
+            if !text.is_empty() {
+                println!("{}: {}", command, text);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replace the map in the configured format and run its hooks if the content changed, while
+    /// holding the maps lock. The previous map is restored if a hook fails. Return the changes.
+    pub async fn write(
+        &self,
+        config: &Config,
+        data: &str,
+        force: bool,
+        recipients: &SharedRecipients,
+        _lock: &MapsLock,
+    ) -> Result<MapDiff> {
+        let map = self.path(config);
+        let diff = self.check(config, data, force, recipients).await?;
+        // disabled with an empty path (socketmap lookups)
+        if diff.is_empty() || map.is_empty() {
+            return Ok(diff);
+        }
+        // write the map file
+        let content = config
+            .map_format
+            .render(self, &canonical(data))
+            .into_iter()
+            .map(|line| line + "\n")
+            .collect::<String>();
+        let previous = fs::read(map).await.ok();
+        write_atomic(Path::new(map), content.as_bytes())
+            .await
+            .with_context(|| format!("Can't write {}", map))?;
+        if let Err(e) = self.run_hooks(config).await {
+            // keep the previous map
+            match previous {
+                Some(previous) => {
+                    write_atomic(Path::new(map), &previous).await?;
+                    if let Err(e) = self.run_hooks(config).await {
+                        eprintln!("can't restore the previous {} map: {}", self.name(), e);
+                    }
+                }
+                None => fs::remove_file(map).await?,
+            }
+            return Err(anyhow!("{} map not updated: {}", self.name(), e));
+        }
+        println!(
+            "{} map updated: {} added, {} removed",
+            self.name(),
+            diff.added.len(),
+            diff.removed.len()
+        );
+        Ok(diff)
+    }
+
+    pub async fn get(&self, config: &Config) -> Result<String> {
+        match self.fetch(config, None).await? {
+            Some((data, _)) => Ok(data),
+            None => Err(anyhow!("Unexpected 304 from odoo")),
+        }
+    }
+
+    /// Get the map from odoo with its etag, or None if it didn't change since the given etag
+    pub async fn fetch(
+        &self,
+        config: &Config,
+        etag: Option<&str>,
+    ) -> Result<Option<(String, Option<String>)>> {
+        let url = format!("https://{}/mail_delivery/{}", &config.host, self.name());
+        let mut request = client()
+            .get(&url)
+            .header("X-Mail-Token", &config.token)
+            .timeout(Duration::from_secs(config.timeout));
+        if let Some(etag) = etag {
+            request = request.header("If-None-Match", etag);
+        }
+        let resp = request.send().await?;
+        let code = resp.status();
+        if code == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let etag = resp
+            .headers()
+            .get("etag")
+            .and_then(|etag| etag.to_str().ok())
+            .map(str::to_string);
+        let text = resp.text().await?;
+        if code.is_success() {
+            Ok(Some((text, etag)))
         } else {
-            let code = resp.status();
-            let details = resp.into_string()?;
-            Err(Error::new(HttpError::new(code, &details)))
+            Err(Error::new(HttpError::new(code.as_u16(), &text)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmd::{transport::with_nexthop, webhook::maps},
+        config::test_config,
+        recipients::Recipients,
+    };
+
+    #[test]
+    fn canonical_form() {
+        let entries = canonical("b@x.com  a@x.com\n\n a@x.com\ta@x.com \nb@x.com a@x.com\n");
+        assert_eq!(
+            entries.into_iter().collect::<Vec<String>>(),
+            ["a@x.com a@x.com", "b@x.com a@x.com"]
+        );
+    }
+
+    /// Aliases map of n aliases of the same account
+    fn aliases(n: usize) -> String {
+        (0..n)
+            .map(|i| format!("{}@x.com a@x.com\n", i))
+            .collect::<String>()
+    }
+
+    #[tokio::test]
+    async fn check_shrink() {
+        let config = test_config("shrink");
+        let recipients = Recipients::default().shared();
+        fs::write(&config.aliases, aliases(10)).await.unwrap();
+        let check = |data: String, force| {
+            let (config, recipients) = (config.clone(), recipients.clone());
+            async move {
+                MapType::Aliases
+                    .check(&config, &data, force, &recipients)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        };
+        // up to max_shrink percent of the entries can be removed
+        assert_eq!(check(aliases(5), false).await.unwrap().removed.len(), 5);
+        assert_eq!(
+            check(aliases(4), false).await.unwrap_err(),
+            "aliases map would shrink from 10 to 4 entries"
+        );
+        assert_eq!(
+            check(String::new(), false).await.unwrap_err(),
+            "empty aliases map"
+        );
+        assert!(check(String::new(), true).await.is_ok());
+        assert_eq!(check(aliases(12), false).await.unwrap().added.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn check_entries() {
+        let config = test_config("entries");
+        let recipients = Recipients::default().shared();
+        for (map, data) in [
+            (MapType::Aliases, "a@x.com"),
+            (MapType::Aliases, "a@x.com b@x.com c@x.com"),
+            (MapType::Aliases, "a b"),
+            (MapType::Transport, "a@x.com"),
+            (MapType::Transport, "a lmtp:unix:/s"),
+        ] {
+            let err = map
+                .check(&config, data, true, &recipients)
+                .await
+                .unwrap_err();
+            assert!(err.downcast_ref::<MapError>().is_some());
         }
     }
+
+    #[tokio::test]
+    async fn transport_from_webhook_then_odoo() {
+        let config = test_config("transport");
+        let (_, transport) = maps(&config, "a@x.com: [b]\nc@x.com: [d]\ne@y.com: []").unwrap();
+        let recipients = Recipients::default().shared();
+        let lock = MapsLock::acquire(&config).await.unwrap();
+        MapType::Transport
+            .write(&config, &transport, false, &recipients, &lock)
+            .await
+            .unwrap();
+        // same addresses from the transport endpoint: nothing to change
+        let data = with_nexthop(&config, "a@x.com c@x.com e@y.com");
+        let diff = MapType::Transport
+            .check(&config, &data, false, &recipients)
+            .await
+            .unwrap();
+        assert!(diff.is_empty());
+    }
+
+    #[tokio::test]
+    async fn check_without_map_files() {
+        let mut config = test_config("memory");
+        config.aliases = String::new();
+        config.transport = String::new();
+        let recipients =
+            Recipients::new("b@x.com a@x.com\nc@x.com a@x.com", "a@x.com lmtp:unix:/s").shared();
+        // compared with the aliases in memory
+        let err = MapType::Aliases
+            .check(&config, "", false, &recipients)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "empty aliases map");
+        let diff = MapType::Aliases
+            .check(
+                &config,
+                "b@x.com a@x.com\nd@x.com a@x.com",
+                false,
+                &recipients,
+            )
+            .await
+            .unwrap();
+        assert_eq!(diff.added, ["d@x.com a@x.com"]);
+        assert_eq!(diff.removed, ["c@x.com a@x.com"]);
+    }
+
+    #[tokio::test]
+    async fn write_restores_previous_map() {
+        let mut config = test_config("restore");
+        config.hooks.aliases = Some(vec!["false".to_string()]);
+        let recipients = Recipients::default().shared();
+        let write = |data: &'static str| {
+            let (config, recipients) = (config.clone(), recipients.clone());
+            async move {
+                let lock = MapsLock::acquire(&config).await.unwrap();
+                MapType::Aliases
+                    .write(&config, data, false, &recipients, &lock)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        };
+        // no previous map
+        let err = write("b@x.com a@x.com").await.unwrap_err();
+        assert!(err.starts_with("aliases map not updated: `false` failed"));
+        assert!(!Path::new(&config.aliases).exists());
+        // previous map rewritten
+        fs::write(&config.aliases, "b@x.com a@x.com\n")
+            .await
+            .unwrap();
+        let err = write("b@x.com a@x.com\nc@x.com a@x.com").await.unwrap_err();
+        assert!(err.starts_with("aliases map not updated: "));
+        assert_eq!(
+            fs::read_to_string(&config.aliases).await.unwrap(),
+            "b@x.com a@x.com\n"
+        );
+        let dir = Path::new(&config.aliases).parent().unwrap();
+        let mut files = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".tmp"));
+        assert_eq!(files.next(), None);
+    }
 }